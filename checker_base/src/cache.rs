@@ -0,0 +1,254 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::checksums::Repo;
+use crate::error::Error;
+
+/// The length of a full changeset hash.
+const NODE_HASH_LEN: usize = 40;
+
+/// The default maximum size of the cache, in bytes.
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The extension of files being written to the cache.
+const TMP_EXTENSION: &str = "tmp";
+
+/// An on-disk cache of files downloaded from the repositories.
+///
+/// Files are only cached when they're fetched at a full changeset hash, since
+/// the content of a file at any other revision (e.g. "tip") can change. Each
+/// file is stored at `<repo>/<hash>/<path>` under the cache directory.
+///
+/// Once the files in the cache add up to more than its maximum size, the least
+/// recently used ones are evicted. Several processes can share the same cache
+/// directory.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// A file in the cache, considered for eviction.
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl FileCache {
+    /// Use the given directory for the cache, and evict files once they add
+    /// up to more than `max_size` bytes. The directory is created when the
+    /// first file is cached.
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> FileCache {
+        FileCache {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    /// Whether the content of files at the given revision can't change, i.e.
+    /// the revision is a full changeset hash.
+    pub fn is_cacheable(rev: &str) -> bool {
+        rev.len() == NODE_HASH_LEN && rev.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    /// The path to the cached copy of the given file.
+    fn entry_path(&self, repo: Repo, rev: &str, path: &str) -> PathBuf {
+        self.dir
+            .join(repo.name())
+            .join(rev.to_ascii_lowercase())
+            .join(path)
+    }
+
+    /// Read the cached copy of the given file, if there is one, and mark it
+    /// as recently used.
+    ///
+    /// A cache that can't be read is treated as empty, so failures are only
+    /// logged.
+    pub(crate) async fn get(&self, repo: Repo, rev: &str, path: &str) -> Option<Vec<u8>> {
+        if !Self::is_cacheable(rev) {
+            return None;
+        }
+
+        let entry = self.entry_path(repo, rev, path);
+        let content = match fs::read(&entry).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::warn!("Could not read cached file {}: {err}", entry.display());
+                return None;
+            }
+        };
+
+        log::debug!("Using cached copy of {}", entry.display());
+
+        // The modification time is used as the last time the file was used,
+        // since access times aren't reliably updated.
+        if let Err(err) = touch(&entry).await {
+            log::warn!("Could not update cached file {}: {err}", entry.display());
+        }
+
+        Some(content)
+    }
+
+    /// Start storing a copy of the given file, if its revision is cacheable.
+    /// The content is written with [`CacheWriter::write`] as it's downloaded,
+    /// and only added to the cache once [`CacheWriter::commit`] is called.
+    pub(crate) async fn writer(
+        &self,
+        repo: Repo,
+        rev: &str,
+        path: &str,
+    ) -> Result<Option<CacheWriter>, Error> {
+        if !Self::is_cacheable(rev) {
+            return Ok(None);
+        }
+
+        let entry = self.entry_path(repo, rev, path);
+        if let Some(parent) = entry.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut tmp = entry.clone().into_os_string();
+        tmp.push(format!(".{}.{TMP_EXTENSION}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+        let file = fs::File::create(&tmp).await?;
+
+        Ok(Some(CacheWriter {
+            cache: self.clone(),
+            entry,
+            tmp: Some(tmp),
+            file,
+        }))
+    }
+
+    /// Remove the least recently used files until the cache fits within its
+    /// maximum size.
+    async fn evict(&self) -> Result<(), Error> {
+        let mut entries = vec![];
+        list_entries(&self.dir, &mut entries).await?;
+
+        let mut total_size = entries.iter().map(|entry| entry.size).sum::<u64>();
+        if total_size <= self.max_size {
+            return Ok(());
+        }
+
+        entries.sort_by_key(|entry| entry.last_used);
+        for entry in entries {
+            if total_size <= self.max_size {
+                break;
+            }
+
+            log::debug!("Evicting {} from the cache", entry.path.display());
+
+            match fs::remove_file(&entry.path).await {
+                Ok(()) => {}
+                // Another process sharing the cache might have evicted it.
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            total_size = total_size.saturating_sub(entry.size);
+
+            // Clean up the file's directory if it's now empty. This fails if
+            // it isn't, which is fine.
+            if let Some(parent) = entry.path.parent() {
+                let _ = fs::remove_dir(parent).await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A file being added to the cache.
+///
+/// The file is written to a temporary file first and then renamed, so that
+/// concurrent readers never see a partially written file. The temporary file
+/// is removed if the writer is dropped without being committed, e.g. because
+/// the download failed.
+pub(crate) struct CacheWriter {
+    cache: FileCache,
+    entry: PathBuf,
+
+    /// The temporary file being written, until the writer is committed.
+    tmp: Option<PathBuf>,
+    file: fs::File,
+}
+
+impl CacheWriter {
+    /// Append the given chunk to the file.
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.file.write_all(chunk).await?;
+
+        Ok(())
+    }
+
+    /// Add the file to the cache, then evict the least recently used files if
+    /// the cache is too large.
+    pub async fn commit(mut self) -> Result<(), Error> {
+        self.file.flush().await?;
+
+        if let Some(tmp) = &self.tmp {
+            fs::rename(tmp, &self.entry).await?;
+            self.tmp = None;
+        }
+
+        self.cache.evict().await
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if let Some(tmp) = &self.tmp {
+            let _ = std::fs::remove_file(tmp);
+        }
+    }
+}
+
+/// Set the modification time of the given file to now.
+async fn touch(path: &Path) -> Result<(), std::io::Error> {
+    let file = fs::File::options().write(true).open(path).await?;
+    file.into_std().await.set_modified(SystemTime::now())
+}
+
+/// List the files in the given directory and its subdirectories, skipping the
+/// ones being written.
+async fn list_entries(dir: &Path, entries: &mut Vec<CacheEntry>) -> Result<(), Error> {
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut read_dir = match fs::read_dir(&dir).await {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_none_or(|ext| ext != TMP_EXTENSION) {
+                entries.push(CacheEntry {
+                    path,
+                    size: metadata.len(),
+                    last_used: metadata.modified()?,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
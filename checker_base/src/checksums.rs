@@ -2,14 +2,21 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use serde::Deserialize;
+use std::time::Instant;
+
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha512};
 
+use crate::cache::FileCache;
 use crate::error::Error;
+use crate::revision::{ResolvedRev, resolve_change_set, resolve_rev};
+use crate::validation::validate_file;
 
 /// The content of the `checksums.json` file on comm-central.
 #[derive(Debug, Clone, Deserialize)]
-struct CommCentralChecksums {
+#[serde(deny_unknown_fields)]
+pub(crate) struct CommCentralChecksums {
     mc_workspace_toml: String,
     mc_gkrust_toml: String,
     mc_hack_toml: String,
@@ -17,7 +24,8 @@ struct CommCentralChecksums {
 }
 
 /// The repository on hg.mozilla.org to fetch a given file from.
-enum Repo {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Repo {
     /// The Firefox (mozilla-unified) repository.
     Firefox,
 
@@ -25,110 +33,422 @@ enum Repo {
     Thunderbird,
 }
 
+impl Repo {
+    /// The name of the repository on the Mercurial web frontend.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Repo::Firefox => "mozilla-central",
+            Repo::Thunderbird => "comm-central",
+        }
+    }
+}
+
 /// The file on comm-central containing the checksums to compare.
-const CC_CHECKSUMS_PATH: &str = "rust/checksums.json";
+pub(crate) const CC_CHECKSUMS_PATH: &str = "rust/checksums.json";
+
+/// The lock file for the Rust workspace on comm-central.
+pub(crate) const CC_CARGO_LOCK_PATH: &str = "rust/Cargo.lock";
 
 /// The files on mozilla-central to compare the checksums of.
 const MC_WORKSPACE_TOML_PATH: &str = "Cargo.toml";
 const MC_GKRUST_TOML_PATH: &str = "toolkit/library/rust/shared/Cargo.toml";
 const MC_HACK_TOML_PATH: &str = "build/workspace-hack/Cargo.toml";
-const MC_CARGO_LOCK_PATH: &str = "Cargo.lock";
+pub(crate) const MC_CARGO_LOCK_PATH: &str = "Cargo.lock";
+
+/// The mozilla-central files whose checksums are compared.
+const MC_CHECKED_PATHS: [&str; 4] = [
+    MC_WORKSPACE_TOML_PATH,
+    MC_GKRUST_TOML_PATH,
+    MC_HACK_TOML_PATH,
+    MC_CARGO_LOCK_PATH,
+];
 
-/// The revisions to use when querying files on hg.mozilla.org.
-#[derive(Default)]
+/// All the files the checks download, along with the repository they're in.
+pub const TRACKED_FILES: [(Repo, &str); 6] = [
+    (Repo::Thunderbird, CC_CHECKSUMS_PATH),
+    (Repo::Thunderbird, CC_CARGO_LOCK_PATH),
+    (Repo::Firefox, MC_WORKSPACE_TOML_PATH),
+    (Repo::Firefox, MC_GKRUST_TOML_PATH),
+    (Repo::Firefox, MC_HACK_TOML_PATH),
+    (Repo::Firefox, MC_CARGO_LOCK_PATH),
+];
+
+/// The revision to use for a repository if none is specified.
+const DEFAULT_REV: &str = "tip";
+
+/// The Mercurial web frontend to use for a repository if none is specified.
+pub const DEFAULT_HOST: &str = "https://hg-edge.mozilla.org";
+
+/// The revisions to use when querying files on hg.mozilla.org, and optionally
+/// the hosts to query them on and the cache to use.
+#[derive(Debug, Clone, Default)]
 pub struct ChangeSet {
     /// The revision for mozilla-central. Defaults to "tip".
     pub moz_rev: Option<String>,
 
     /// The revision for comm-central. Defaults to "tip".
     pub tb_rev: Option<String>,
+
+    /// The base URL of the Mercurial web frontend serving mozilla-central.
+    /// Defaults to [`DEFAULT_HOST`].
+    pub moz_host: Option<String>,
+
+    /// The base URL of the Mercurial web frontend serving comm-central.
+    /// Defaults to [`DEFAULT_HOST`].
+    pub tb_host: Option<String>,
+
+    /// The cache to look files up in before downloading them, and to store
+    /// them in afterwards. Files aren't cached if this is `None`.
+    pub cache: Option<FileCache>,
+
+    /// The maximum size of a downloaded file, in bytes. Downloads are aborted
+    /// once they exceed it, e.g. if the server sends a large HTML error page
+    /// instead of the file. There is no limit if this is `None`.
+    pub max_file_size: Option<u64>,
 }
 
-/// Download the comm-central file containing the SHA512 checksums to compare,
-/// then check if they match the checksums of the relevant mozilla-central
-/// files.
-///
-/// This function returns whether the checksum of all files match the checksums
-/// stored in comm-central.
-pub async fn verify_checksums_match(change_set: ChangeSet) -> Result<bool, Error> {
-    // A helper macro that generates the URL to a raw file in the given repo on
-    // the Mercurial web frontend.
-    macro_rules! generate_url {
-        ($repo:expr, $path:tt) => {{
-            let (repo_name, rev) = match $repo {
-                Repo::Firefox => ("mozilla-central", change_set.moz_rev.clone()),
-                Repo::Thunderbird => ("comm-central", change_set.tb_rev.clone()),
-            };
+impl ChangeSet {
+    /// The revision to use for mozilla-central.
+    pub fn moz_rev(&self) -> &str {
+        self.moz_rev.as_deref().unwrap_or(DEFAULT_REV)
+    }
+
+    /// The revision to use for comm-central.
+    pub fn tb_rev(&self) -> &str {
+        self.tb_rev.as_deref().unwrap_or(DEFAULT_REV)
+    }
+
+    /// The revision to use for the given repo.
+    pub fn rev(&self, repo: Repo) -> &str {
+        match repo {
+            Repo::Firefox => self.moz_rev(),
+            Repo::Thunderbird => self.tb_rev(),
+        }
+    }
+
+    /// The base URL of the Mercurial web frontend serving the given repo.
+    pub fn host(&self, repo: Repo) -> &str {
+        let host = match repo {
+            Repo::Firefox => &self.moz_host,
+            Repo::Thunderbird => &self.tb_host,
+        };
+
+        host.as_deref()
+            .unwrap_or(DEFAULT_HOST)
+            .trim_end_matches('/')
+    }
+
+    /// Generate the URL to a raw file in the given repo on the Mercurial web
+    /// frontend, at the given revision.
+    pub(crate) fn raw_file_url(&self, repo: Repo, rev: &str, path: &str) -> String {
+        let url = format!("{}/{}/raw-file/{rev}/{path}", self.host(repo), repo.name());
+
+        log::debug!("Fetching file: {}", url);
+
+        url
+    }
+}
+
+/// The result of comparing the checksum of a single mozilla-central file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileCheckResult {
+    /// The path of the file in mozilla-central.
+    pub path: String,
+
+    /// The checksum stored in comm-central.
+    pub expected_checksum: String,
+
+    /// The checksum of the file downloaded from mozilla-central.
+    pub actual_checksum: String,
+
+    /// How long it took to download the file from mozilla-central, in
+    /// milliseconds.
+    #[serde(default)]
+    pub fetch_duration_ms: u64,
+
+    /// The size of the file, in bytes.
+    #[serde(default)]
+    pub size: u64,
+
+    /// The content type the file was served with. This is `None` if the file
+    /// was read from the cache, or if the server didn't send one.
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+impl FileCheckResult {
+    /// Whether the checksum of the file matches the one stored in
+    /// comm-central.
+    pub fn matches(&self) -> bool {
+        self.expected_checksum == self.actual_checksum
+    }
+}
+
+/// The checksum of a mozilla-central file, computed at a given revision.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChecksum {
+    /// The path of the file in mozilla-central.
+    pub path: String,
+
+    /// The SHA512 checksum of the file, hex-encoded.
+    pub checksum: String,
+}
+
+/// The result of comparing the checksums of all relevant mozilla-central files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckReport {
+    /// The mozilla-central changeset the files were fetched at.
+    pub moz_rev: ResolvedRev,
+
+    /// The comm-central changeset the checksums were fetched at.
+    pub tb_rev: ResolvedRev,
 
-            let url = format!(
-                "https://hg-edge.mozilla.org/{}/raw-file/{}/{}",
-                repo_name,
-                rev.unwrap_or("tip".to_string()),
-                $path
-            );
+    /// The SHA512 checksum of the comm-central checksums file itself, as
+    /// returned by [`checksums_file_digest`].
+    pub checksums_digest: String,
 
-            log::debug!("Fetching file: {}", url);
+    /// The result for each mozilla-central file.
+    pub files: Vec<FileCheckResult>,
+}
+
+impl CheckReport {
+    /// Whether the checksums of all files match the checksums stored in
+    /// comm-central.
+    pub fn checksums_match(&self) -> bool {
+        self.files.iter().all(FileCheckResult::matches)
+    }
 
-            url
-        }};
+    /// The results for the files whose checksums don't match.
+    pub fn mismatched_files(&self) -> impl Iterator<Item = &FileCheckResult> {
+        self.files.iter().filter(|file| !file.matches())
     }
+}
+
+/// Download the comm-central file containing the SHA512 checksums to compare,
+/// then check if they match the checksums of the relevant mozilla-central
+/// files.
+///
+/// The revisions of the change set are resolved to changeset hashes first, and
+/// all the files are fetched at these hashes, so that a push landing during the
+/// check can't result in files from different changesets being compared.
+///
+/// This function returns a report with the result of the comparison for each
+/// file; see [`CheckReport::checksums_match`] to know whether the checksums of
+/// all files match the checksums stored in comm-central.
+pub async fn verify_checksums_match(change_set: ChangeSet) -> Result<CheckReport, Error> {
+    let resolved = resolve_change_set(&change_set).await?;
+    let change_set = resolved.change_set;
 
     // Download the checksums file from comm-central. Downloading this
     // statically-served file should only result in 200 responses, so propagate
     // an error if we get an HTTP error.
-    let checksums: CommCentralChecksums =
-        reqwest::get(generate_url!(Repo::Thunderbird, CC_CHECKSUMS_PATH))
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
+    let checksums_file =
+        fetch_validated_file(&change_set, Repo::Thunderbird, CC_CHECKSUMS_PATH).await?;
+    let checksums: CommCentralChecksums = serde_json::from_slice(&checksums_file.content)?;
 
     // Download all the relevant files, then compare their checksums to the ones
     // we expect.
-    let futs = vec![
-        compare_checksum_for_file(
-            generate_url!(Repo::Firefox, MC_WORKSPACE_TOML_PATH),
-            &checksums.mc_workspace_toml,
-        ),
-        compare_checksum_for_file(
-            generate_url!(Repo::Firefox, MC_GKRUST_TOML_PATH),
-            &checksums.mc_gkrust_toml,
-        ),
-        compare_checksum_for_file(
-            generate_url!(Repo::Firefox, MC_HACK_TOML_PATH),
-            &checksums.mc_hack_toml,
-        ),
-        compare_checksum_for_file(
-            generate_url!(Repo::Firefox, MC_CARGO_LOCK_PATH),
-            &checksums.mc_cargo_lock,
-        ),
+    let expected_checksums = [
+        &checksums.mc_workspace_toml,
+        &checksums.mc_gkrust_toml,
+        &checksums.mc_hack_toml,
+        &checksums.mc_cargo_lock,
     ];
+    let futs =
+        MC_CHECKED_PATHS
+            .into_iter()
+            .zip(expected_checksums)
+            .map(|(path, expected_checksum)| {
+                compare_checksum_for_file(&change_set, path, expected_checksum)
+            });
 
-    let checksum_results = futures::future::join_all(futs)
+    let files = futures::future::join_all(futs)
         .await
         .into_iter()
-        .collect::<Result<Vec<bool>, Error>>()?;
+        .collect::<Result<Vec<FileCheckResult>, Error>>()?;
+
+    Ok(CheckReport {
+        moz_rev: resolved.moz,
+        tb_rev: resolved.tb,
+        checksums_digest: checksums_file.checksum,
+        files,
+    })
+}
+
+/// Download the relevant mozilla-central files, and compute their SHA512
+/// checksums, i.e. the ones comm-central should store to be in sync with this
+/// revision.
+///
+/// If the mozilla-central revision is symbolic, the files could come from
+/// different changesets; use a change set pinned with
+/// [`resolve_change_set`] to avoid this.
+pub async fn compute_checksums(change_set: &ChangeSet) -> Result<Vec<FileChecksum>, Error> {
+    let futs = MC_CHECKED_PATHS.into_iter().map(|path| async move {
+        let file = fetch_validated_file(change_set, Repo::Firefox, path).await?;
+
+        Ok(FileChecksum {
+            path: path.to_string(),
+            checksum: file.checksum,
+        })
+    });
+
+    futures::future::join_all(futs).await.into_iter().collect()
+}
+
+/// A file fetched by [`fetch_validated_file`].
+struct FetchedFile {
+    content: Vec<u8>,
+
+    /// The SHA512 checksum of the file, hex-encoded.
+    checksum: String,
+
+    /// The content type the file was served with, if it was downloaded.
+    content_type: Option<String>,
+}
+
+/// Download the file at the given path in the given repo, at the revision to
+/// use for this repo.
+pub async fn fetch_file(change_set: &ChangeSet, repo: Repo, path: &str) -> Result<Vec<u8>, Error> {
+    Ok(fetch_validated_file(change_set, repo, path).await?.content)
+}
+
+/// Download the file at the given path in the given repo, at the revision to
+/// use for this repo, check that its content looks like the file that was asked
+/// for, and compute its SHA512 checksum.
+///
+/// The whole file is kept in memory, since it needs to be validated (and might
+/// need to be parsed) before it can be used. The change set's maximum file size
+/// (if any) bounds how much is buffered: the download is aborted as soon as it
+/// exceeds it.
+///
+/// If the change set has a cache, the revision is first resolved to a
+/// changeset hash (unless it already is one), so that the file can be looked
+/// up in the cache. Otherwise, it is written to the cache while it's being
+/// downloaded, but only added to it once its content is found to be valid.
+async fn fetch_validated_file(
+    change_set: &ChangeSet,
+    repo: Repo,
+    path: &str,
+) -> Result<FetchedFile, Error> {
+    let (rev, mut writer) = match &change_set.cache {
+        Some(cache) => {
+            let rev = match FileCache::is_cacheable(change_set.rev(repo)) {
+                true => change_set.rev(repo).to_string(),
+                false => resolve_rev(change_set, repo).await?.node,
+            };
+
+            if let Some(content) = cache.get(repo, &rev, path).await {
+                // The file might have been cached before its content was
+                // validated.
+                validate_file(repo, path, None, &content)?;
+
+                return Ok(FetchedFile {
+                    checksum: hex::encode(Sha512::digest(&content)),
+                    content,
+                    content_type: None,
+                });
+            }
+
+            // Failing to cache the file shouldn't fail the check.
+            let writer = cache.writer(repo, &rev, path).await.unwrap_or_else(|err| {
+                log::warn!("Could not cache {}/{path} at {rev}: {err}", repo.name());
+                None
+            });
+
+            (rev, writer)
+        }
+        None => (change_set.rev(repo).to_string(), None),
+    };
+
+    let url = change_set.raw_file_url(repo, &rev, path);
+    let too_large = |max_size| Error::FileTooLarge {
+        url: url.clone(),
+        max_size,
+    };
+
+    let mut response = reqwest::get(&url).await?.error_for_status()?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string);
+
+    // Bail out early if the server announces a file that's too large.
+    if let (Some(max_size), Some(length)) = (change_set.max_file_size, response.content_length())
+        && length > max_size
+    {
+        return Err(too_large(max_size));
+    }
+
+    let mut content = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if let Some(max_size) = change_set.max_file_size
+            && (content.len() + chunk.len()) as u64 > max_size
+        {
+            return Err(too_large(max_size));
+        }
+
+        content.extend_from_slice(&chunk);
+
+        if let Some(cache_writer) = &mut writer
+            && let Err(err) = cache_writer.write(&chunk).await
+        {
+            log::warn!("Could not cache {}/{path} at {rev}: {err}", repo.name());
+            writer = None;
+        }
+    }
+
+    // Dropping the cache writer on error discards what it wrote.
+    validate_file(repo, path, content_type.as_deref(), &content)?;
+
+    if let Some(writer) = writer
+        && let Err(err) = writer.commit().await
+    {
+        log::warn!("Could not cache {}/{path} at {rev}: {err}", repo.name());
+    }
+
+    Ok(FetchedFile {
+        checksum: hex::encode(Sha512::digest(&content)),
+        content,
+        content_type,
+    })
+}
+
+/// Download the comm-central file containing the checksums to compare, and
+/// return its own SHA512 checksum.
+///
+/// This can be used to find out whether the checksums stored in comm-central
+/// changed, without downloading all of the mozilla-central files.
+pub async fn checksums_file_digest(change_set: &ChangeSet) -> Result<String, Error> {
+    let file = fetch_validated_file(change_set, Repo::Thunderbird, CC_CHECKSUMS_PATH).await?;
 
-    Ok(!checksum_results.contains(&false))
+    Ok(file.checksum)
 }
 
-/// Download the file at the given URL, then compare its SHA512 checksum to the
-/// one that is expected as per the comm-central checksums file.
-async fn compare_checksum_for_file(url: String, expected_checksum: &str) -> Result<bool, Error> {
-    let bytes = reqwest::get(&url)
-        .await?
-        .error_for_status()?
-        .bytes()
-        .await?;
-    let checksum = Sha512::digest(bytes);
-    let checksum = hex::encode(checksum);
+/// Download the given mozilla-central file, then compare its SHA512 checksum to
+/// the one that is expected as per the comm-central checksums file.
+async fn compare_checksum_for_file(
+    change_set: &ChangeSet,
+    path: &str,
+    expected_checksum: &str,
+) -> Result<FileCheckResult, Error> {
+    let start = Instant::now();
+    let file = fetch_validated_file(change_set, Repo::Firefox, path).await?;
+    let fetch_duration = start.elapsed();
 
     log::debug!(
         "Comparing checksums for {}: {} == {}",
-        url,
+        path,
         expected_checksum,
-        checksum
+        file.checksum
     );
 
-    Ok(expected_checksum == checksum)
+    Ok(FileCheckResult {
+        path: path.to_string(),
+        expected_checksum: expected_checksum.to_string(),
+        actual_checksum: file.checksum,
+        fetch_duration_ms: fetch_duration.as_millis() as u64,
+        size: file.content.len() as u64,
+        content_type: file.content_type,
+    })
 }
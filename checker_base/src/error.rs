@@ -14,7 +14,23 @@ pub enum Error {
     Network(#[from] reqwest::Error),
     Io(#[from] io::Error),
     Json(#[from] serde_json::Error),
+    Toml(#[from] toml::de::Error),
     UrlParse(#[from] url::ParseError),
+
+    /// A downloaded file was larger than the maximum size allowed, which
+    /// usually means the server sent something other than the file.
+    FileTooLarge {
+        url: String,
+        max_size: u64,
+    },
+
+    /// A fetched file doesn't look like the file that was asked for, e.g.
+    /// because the server sent an error page instead. This is different from
+    /// the file having changed, since its checksum is meaningless.
+    InvalidContent {
+        file: String,
+        reason: String,
+    },
 }
 
 impl Display for Error {
@@ -46,7 +62,14 @@ impl Display for Error {
             }
             Error::Io(error) => write!(f, "I/O error: {error}"),
             Error::Json(error) => write!(f, "JSON (de)serialization error: {error}"),
+            Error::Toml(error) => write!(f, "TOML deserialization error: {error}"),
             Error::UrlParse(error) => write!(f, "URL parse error: {error}"),
+            Error::FileTooLarge { url, max_size } => {
+                write!(f, "file larger than {max_size} bytes (URL: {url})")
+            }
+            Error::InvalidContent { file, reason } => {
+                write!(f, "invalid content for {file}: {reason}")
+            }
         }
     }
 }
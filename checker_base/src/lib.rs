@@ -2,5 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+pub mod cache;
 pub mod checksums;
 pub mod error;
+pub mod lockfile;
+pub mod pushlog;
+pub mod revision;
+mod validation;
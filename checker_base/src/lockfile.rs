@@ -0,0 +1,106 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+use crate::checksums::{CC_CARGO_LOCK_PATH, ChangeSet, MC_CARGO_LOCK_PATH, Repo, fetch_file};
+use crate::error::Error;
+
+/// The parts of a `Cargo.lock` file we care about.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct LockFile {
+    #[serde(default)]
+    pub package: Vec<LockedPackage>,
+}
+
+/// A package in a `Cargo.lock` file.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct LockedPackage {
+    name: String,
+    version: String,
+}
+
+/// A crate whose locked version(s) differ between mozilla-central and
+/// comm-central.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrateChange {
+    /// The name of the crate.
+    pub name: String,
+
+    /// The version(s) of the crate locked in comm-central. This is empty if
+    /// mozilla-central added a dependency on the crate.
+    pub tb_versions: Vec<String>,
+
+    /// The version(s) of the crate locked in mozilla-central.
+    pub moz_versions: Vec<String>,
+}
+
+impl Display for CrateChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.tb_versions.is_empty() {
+            true => write!(f, "{} (new, {})", self.name, self.moz_versions.join(", ")),
+            false => write!(
+                f,
+                "{} {} -> {}",
+                self.name,
+                self.tb_versions.join(", "),
+                self.moz_versions.join(", ")
+            ),
+        }
+    }
+}
+
+/// Compare the crates locked in the mozilla-central and comm-central
+/// `Cargo.lock` files, and list the ones whose versions differ.
+///
+/// The comm-central lock file also includes crates only comm-central depends
+/// on, so only crates that mozilla-central depends on are considered: they are
+/// listed if comm-central locks them to different versions, or doesn't depend
+/// on them at all.
+pub async fn changed_crates(change_set: &ChangeSet) -> Result<Vec<CrateChange>, Error> {
+    let (moz_lock, tb_lock) = futures::try_join!(
+        fetch_lock_file(change_set, Repo::Firefox, MC_CARGO_LOCK_PATH),
+        fetch_lock_file(change_set, Repo::Thunderbird, CC_CARGO_LOCK_PATH),
+    )?;
+
+    let changes = moz_lock
+        .into_iter()
+        .filter_map(|(name, moz_versions)| {
+            let tb_versions = tb_lock.get(&name).cloned().unwrap_or_default();
+
+            (tb_versions != moz_versions).then(|| CrateChange {
+                name,
+                tb_versions: tb_versions.into_iter().collect(),
+                moz_versions: moz_versions.into_iter().collect(),
+            })
+        })
+        .collect();
+
+    Ok(changes)
+}
+
+/// Download and parse the `Cargo.lock` file at the given path in the given
+/// repo, and return the version(s) locked for each crate.
+async fn fetch_lock_file(
+    change_set: &ChangeSet,
+    repo: Repo,
+    path: &str,
+) -> Result<BTreeMap<String, BTreeSet<String>>, Error> {
+    let content = fetch_file(change_set, repo, path).await?;
+
+    let lock_file: LockFile = toml::from_str(&String::from_utf8_lossy(&content))?;
+
+    let mut crates: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for package in lock_file.package {
+        crates
+            .entry(package.name)
+            .or_default()
+            .insert(package.version);
+    }
+
+    Ok(crates)
+}
@@ -0,0 +1,95 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::checksums::{ChangeSet, Repo};
+use crate::error::Error;
+
+/// The response of the `json-pushes` endpoint, in version 2 of its format.
+#[derive(Debug, Deserialize)]
+struct PushlogResponse {
+    pushes: BTreeMap<String, PushlogEntry>,
+}
+
+/// A push in a [`PushlogResponse`].
+#[derive(Debug, Deserialize)]
+struct PushlogEntry {
+    changesets: Vec<String>,
+    date: u64,
+}
+
+/// A push to a repository.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Push {
+    /// The ID of the push in the repository's pushlog.
+    pub id: u64,
+
+    /// When the push happened, as a UNIX timestamp in seconds.
+    pub date: u64,
+
+    /// The hash of the changeset at the head of the push.
+    pub head: String,
+}
+
+/// A bound of a range of pushes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PushBound {
+    /// The push with the given ID.
+    Id(u64),
+
+    /// The given time, as a UNIX timestamp in seconds.
+    Date(u64),
+}
+
+/// List the pushes to the given repository between the given bounds (both
+/// inclusive), oldest first. If `until` is `None`, the range extends to the
+/// latest push.
+pub async fn fetch_pushes(
+    change_set: &ChangeSet,
+    repo: Repo,
+    since: PushBound,
+    until: Option<PushBound>,
+) -> Result<Vec<Push>, Error> {
+    let mut params = vec![("version", "2".to_string()), ("tipsonly", "1".to_string())];
+
+    // `startID` is exclusive, while `endID` is inclusive. Dates are given to
+    // Mercurial as a UNIX timestamp and a UTC offset.
+    match since {
+        PushBound::Id(id) => params.push(("startID", id.saturating_sub(1).to_string())),
+        PushBound::Date(date) => params.push(("startdate", format!("{date} 0"))),
+    }
+    match until {
+        Some(PushBound::Id(id)) => params.push(("endID", id.to_string())),
+        Some(PushBound::Date(date)) => params.push(("enddate", format!("{date} 0"))),
+        None => {}
+    }
+
+    let url = Url::parse_with_params(
+        &format!("{}/{}/json-pushes", change_set.host(repo), repo.name()),
+        &params,
+    )?;
+
+    log::debug!("Fetching pushlog: {url}");
+
+    let response: PushlogResponse = reqwest::get(url).await?.error_for_status()?.json().await?;
+
+    let mut pushes = response
+        .pushes
+        .into_iter()
+        .filter_map(|(id, entry)| {
+            Some(Push {
+                id: id.parse().ok()?,
+                date: entry.date,
+                head: entry.changesets.last()?.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+    pushes.sort_by_key(|push| push.id);
+
+    Ok(pushes)
+}
@@ -0,0 +1,98 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde::{Deserialize, Serialize};
+
+use crate::checksums::{ChangeSet, Repo};
+use crate::error::Error;
+
+/// The parts of the response of the `json-rev` endpoint we care about.
+#[derive(Debug, Deserialize)]
+struct JsonRev {
+    node: String,
+    desc: String,
+
+    /// When the changeset was pushed, as a UNIX timestamp and a UTC offset.
+    /// This is only included by repositories with a pushlog.
+    #[serde(default)]
+    pushdate: Option<(u64, i64)>,
+}
+
+/// The changeset a revision resolved to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResolvedRev {
+    /// The full hash of the changeset.
+    pub node: String,
+
+    /// The first line of the changeset's description.
+    pub description: String,
+
+    /// When the changeset was pushed, as a UNIX timestamp in seconds. This is
+    /// `None` if the repository has no pushlog.
+    pub push_date: Option<u64>,
+}
+
+/// A change set whose revisions were resolved to full changeset hashes, so
+/// that all the files fetched through it come from the same changesets even if
+/// new changesets are pushed in the meantime.
+#[derive(Debug, Clone)]
+pub struct ResolvedChangeSet {
+    /// The change set, with its revisions replaced by the hashes they resolved
+    /// to.
+    pub change_set: ChangeSet,
+
+    /// The changeset the mozilla-central revision resolved to.
+    pub moz: ResolvedRev,
+
+    /// The changeset the comm-central revision resolved to.
+    pub tb: ResolvedRev,
+}
+
+/// Resolve the revision to use for the given repo (which can be symbolic, such
+/// as "tip" or a bookmark) to the changeset it points to.
+pub async fn resolve_rev(change_set: &ChangeSet, repo: Repo) -> Result<ResolvedRev, Error> {
+    let url = format!(
+        "{}/{}/json-rev/{}",
+        change_set.host(repo),
+        repo.name(),
+        change_set.rev(repo)
+    );
+
+    log::debug!("Resolving revision: {url}");
+
+    let rev: JsonRev = reqwest::get(&url).await?.error_for_status()?.json().await?;
+
+    Ok(ResolvedRev {
+        node: rev.node,
+        description: rev.desc.lines().next().unwrap_or_default().to_string(),
+        push_date: rev.pushdate.map(|(timestamp, _)| timestamp),
+    })
+}
+
+/// Resolve the revisions of both repositories once, and return a copy of the
+/// given change set pinned to the resulting changesets.
+pub async fn resolve_change_set(change_set: &ChangeSet) -> Result<ResolvedChangeSet, Error> {
+    let (moz, tb) = futures::try_join!(
+        resolve_rev(change_set, Repo::Firefox),
+        resolve_rev(change_set, Repo::Thunderbird),
+    )?;
+
+    log::debug!(
+        "Resolved mozilla-central {} to {} and comm-central {} to {}",
+        change_set.moz_rev(),
+        moz.node,
+        change_set.tb_rev(),
+        tb.node
+    );
+
+    Ok(ResolvedChangeSet {
+        change_set: ChangeSet {
+            moz_rev: Some(moz.node.clone()),
+            tb_rev: Some(tb.node.clone()),
+            ..change_set.clone()
+        },
+        moz,
+        tb,
+    })
+}
@@ -0,0 +1,58 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::checksums::{CC_CHECKSUMS_PATH, CommCentralChecksums, Repo};
+use crate::error::Error;
+use crate::lockfile::LockFile;
+
+/// The content types the Mercurial web frontend serves its error pages with.
+/// None of the files we fetch should ever be served with these.
+const ERROR_PAGE_CONTENT_TYPES: [&str; 2] = ["text/html", "application/xhtml+xml"];
+
+/// Check that the content of a fetched file looks like the file we asked for,
+/// rather than e.g. an error page served with a 200 status.
+///
+/// `content_type` is the content type the file was served with, if it was
+/// downloaded. The content is then parsed according to the kind of file:
+/// `checksums.json` must contain exactly the expected checksums, lock files
+/// must be valid `Cargo.lock` files, and other TOML files must be valid TOML.
+pub(crate) fn validate_file(
+    repo: Repo,
+    path: &str,
+    content_type: Option<&str>,
+    content: &[u8],
+) -> Result<(), Error> {
+    let invalid = |reason: String| Error::InvalidContent {
+        file: format!("{}/{path}", repo.name()),
+        reason,
+    };
+
+    if let Some(content_type) = content_type {
+        let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+        if ERROR_PAGE_CONTENT_TYPES
+            .iter()
+            .any(|error_type| mime_type.eq_ignore_ascii_case(error_type))
+        {
+            return Err(invalid(format!("served as {content_type}")));
+        }
+    }
+
+    let text = std::str::from_utf8(content).map_err(|err| invalid(err.to_string()))?;
+
+    if path == CC_CHECKSUMS_PATH {
+        serde_json::from_str::<CommCentralChecksums>(text)
+            .map_err(|err| invalid(format!("not a valid checksums file: {err}")))?;
+    } else if path.ends_with("Cargo.lock") {
+        let lock_file = toml::from_str::<LockFile>(text)
+            .map_err(|err| invalid(format!("not a valid lock file: {}", err.message())))?;
+        if lock_file.package.is_empty() {
+            return Err(invalid("no package in the lock file".to_string()));
+        }
+    } else if path.ends_with(".toml") {
+        toml::from_str::<toml::Table>(text)
+            .map_err(|err| invalid(format!("not valid TOML: {}", err.message())))?;
+    }
+
+    Ok(())
+}
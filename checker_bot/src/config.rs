@@ -2,10 +2,31 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::fs;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
 
-use anyhow::Error;
+use anyhow::{Context, Error, bail};
+use chrono::{DateTime, Utc};
+use lettre::message::Mailbox;
+use regex::Regex;
 use serde::Deserialize;
+use toml::{Table, Value};
+use url::Url;
+
+use checker_base::cache::{DEFAULT_MAX_SIZE, FileCache};
+use checker_base::checksums::ChangeSet;
+
+use crate::template::MessageTemplate;
+
+/// The prefix of environment variables that override configuration keys.
+const ENV_PREFIX: &str = "TBRUST_";
+
+/// The separator between nested keys in the names of environment variables that
+/// override configuration keys, e.g. `TBRUST_MATRIX__ACCESS_TOKEN` overrides
+/// `access_token` in the `matrix` section.
+const ENV_KEY_SEPARATOR: &str = "__";
 
 /// The user-defined config for the Matrix bot.
 ///
@@ -14,27 +35,719 @@ use serde::Deserialize;
 pub(crate) struct Config {
     pub store_location: Option<String>,
     pub push_message_substring: String,
+    pub comm_push_message_substring: Option<String>,
+    pub push_id_pattern: Option<PushIdPattern>,
+    #[serde(default = "default_check_debounce_seconds")]
+    pub check_debounce_seconds: u64,
+    #[serde(default = "default_check_timeout_seconds")]
+    pub check_timeout_seconds: u64,
+    pub max_file_size_mb: Option<u64>,
     pub messages: MessagesConfig,
     pub matrix: MatrixConfig,
+    pub escalation: Option<EscalationConfig>,
+    pub schedule: Option<Schedule>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    pub email: Option<EmailConfig>,
+    pub pulse: Option<PulseConfig>,
+    pub cache: Option<CacheConfig>,
+    pub http: Option<HttpConfig>,
+}
+
+fn default_check_debounce_seconds() -> u64 {
+    30
+}
+
+fn default_check_timeout_seconds() -> u64 {
+    600
+}
+
+impl Config {
+    /// Whether the bot is meant to be in the room with the given ID, i.e. it is
+    /// either the room it watches or the room it escalates to.
+    pub fn serves_room(&self, room_id: &str) -> bool {
+        let escalation_room = self
+            .escalation
+            .as_ref()
+            .and_then(|escalation| escalation.room.as_ref())
+            .map(|room| room.room_id.as_str());
+
+        room_id == self.matrix.room_id || Some(room_id) == escalation_room
+    }
+
+    /// The revisions to check, along with the cache to download files through,
+    /// if one is configured, and the maximum size of downloaded files.
+    /// Revisions default to the tip of each repository.
+    pub fn change_set(&self, moz_rev: Option<String>, tb_rev: Option<String>) -> ChangeSet {
+        ChangeSet {
+            moz_rev,
+            tb_rev,
+            cache: self.cache.as_ref().map(|cache| {
+                FileCache::new(&cache.dir, cache.max_size_mb.saturating_mul(1024 * 1024))
+            }),
+            max_file_size: self
+                .max_file_size_mb
+                .map(|size| size.saturating_mul(1024 * 1024)),
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct MessagesConfig {
-    pub deps_out_of_date: String,
-    pub deps_up_to_date: String,
-    pub error_while_comparing_checksums: String,
+    pub deps_out_of_date: MessageTemplate,
+    pub deps_up_to_date: MessageTemplate,
+    pub deps_back_in_sync: Option<MessageTemplate>,
+    pub error_while_comparing_checksums: MessageTemplate,
+    #[serde(default = "default_manual_check_message")]
+    pub manual_check: MessageTemplate,
+    pub introduction: Option<String>,
+}
+
+fn default_manual_check_message() -> MessageTemplate {
+    // The default message only uses known placeholders, so unwrapping should
+    // not panic.
+    MessageTemplate::plain(
+        "Check {check_id} of mozilla-central {moz_rev} against comm-central {comm_rev}, \
+         requested over HTTP: {outcome}",
+    )
+    .unwrap()
+}
+
+/// A regular expression used to extract the ID of a push from the body of a
+/// push notice. The ID is the text matched by its first capture group.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct PushIdPattern(Regex);
+
+impl TryFrom<String> for PushIdPattern {
+    type Error = Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        let regex = Regex::new(&pattern)?;
+        if regex.captures_len() < 2 {
+            bail!("the pattern must include a capture group");
+        }
+
+        Ok(PushIdPattern(regex))
+    }
+}
+
+impl PushIdPattern {
+    /// Extract the ID of a push from the body of its notice, if the pattern
+    /// matches it.
+    pub fn extract(&self, body: &str) -> Option<String> {
+        let captures = self.0.captures(body)?;
+        Some(captures.get(1)?.as_str().to_string())
+    }
+}
+
+/// When to run scheduled checks.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawSchedule")]
+pub(crate) enum Schedule {
+    /// Run a check when the given amount of time has passed since the last
+    /// one.
+    Interval(Duration),
+
+    /// Run a check at the times matching the given cron expression (in UTC).
+    Cron(Box<cron::Schedule>),
+}
+
+/// A schedule, as written in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+struct RawSchedule {
+    interval_minutes: Option<u64>,
+    cron: Option<String>,
+}
+
+impl TryFrom<RawSchedule> for Schedule {
+    type Error = Error;
+
+    fn try_from(raw: RawSchedule) -> Result<Self, Self::Error> {
+        match (raw.interval_minutes, raw.cron) {
+            (Some(0), None) => bail!("interval_minutes must be greater than 0"),
+            (Some(minutes), None) => Ok(Schedule::Interval(Duration::from_secs(minutes * 60))),
+            (None, Some(expression)) => {
+                // Accept the usual five-field syntax, which doesn't include
+                // seconds.
+                let expression = match expression.split_whitespace().count() {
+                    5 => format!("0 {expression}"),
+                    _ => expression,
+                };
+
+                let schedule = cron::Schedule::from_str(&expression)
+                    .with_context(|| format!("invalid cron expression {expression:?}"))?;
+
+                Ok(Schedule::Cron(Box::new(schedule)))
+            }
+            (Some(_), Some(_)) => bail!("only one of interval_minutes and cron can be set"),
+            (None, None) => bail!("one of interval_minutes and cron must be set"),
+        }
+    }
+}
+
+impl Schedule {
+    /// When the next scheduled check is due, given the time of the last check.
+    ///
+    /// Returns `None` if the schedule has no occurrence after the last check.
+    pub fn next_after(&self, last_check: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(interval) => Some(last_check + *interval),
+            Schedule::Cron(schedule) => schedule.after(&last_check).next(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct MatrixConfig {
     pub server_host: String,
+    /// Either this or `access_token_file` must be set, this is enforced when
+    /// loading the configuration.
+    #[serde(default)]
     pub access_token: String,
+    pub access_token_file: Option<String>,
+    pub room_id: String,
+    #[serde(default)]
+    pub allowed_inviters: Vec<String>,
+    #[cfg(feature = "e2ee")]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+#[cfg(feature = "e2ee")]
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct EncryptionConfig {
+    pub pickle_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct EscalationConfig {
+    pub reminder: Option<ReminderConfig>,
+    pub room: Option<EscalationRoomConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ReminderConfig {
+    pub after_hours: u64,
+    #[serde(default)]
+    pub mentions: Vec<String>,
+    pub message: MessageTemplate,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct EscalationRoomConfig {
+    pub after_hours: u64,
     pub room_id: String,
+    #[serde(default)]
+    pub mentions: Vec<String>,
+    pub message: MessageTemplate,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct WebhookConfig {
+    pub url: Url,
+    pub secret: String,
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_webhook_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_timeout_seconds() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct EmailConfig {
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    #[serde(default = "default_starttls")]
+    pub starttls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: Mailbox,
+    pub to: Vec<Mailbox>,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_starttls() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PulseConfig {
+    pub url: String,
+    #[serde(default = "default_pulse_exchange")]
+    pub exchange: String,
+    #[serde(default = "default_pulse_routing_key")]
+    pub routing_key: String,
+    pub queue: String,
+    #[serde(default = "default_pulse_reconnect_delay_seconds")]
+    pub reconnect_delay_seconds: u64,
+}
+
+fn default_pulse_exchange() -> String {
+    "exchange/hgpushes/v2".to_string()
+}
+
+fn default_pulse_routing_key() -> String {
+    "mozilla-central".to_string()
+}
+
+fn default_pulse_reconnect_delay_seconds() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct CacheConfig {
+    pub dir: String,
+    #[serde(default = "default_cache_max_size_mb")]
+    pub max_size_mb: u64,
+}
+
+fn default_cache_max_size_mb() -> u64 {
+    DEFAULT_MAX_SIZE / (1024 * 1024)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct HttpConfig {
+    pub bind_address: String,
+    #[serde(default = "default_max_sync_age_seconds")]
+    pub max_sync_age_seconds: u64,
+    pub trigger_token: Option<String>,
+}
+
+pub(crate) fn default_max_sync_age_seconds() -> u64 {
+    300
 }
 
 /// Read and parse the configuration file at the given path.
+///
+/// Any key in the file can be overridden with an environment variable, see
+/// [`apply_env_overrides`]. If the Matrix access token is to be read from a
+/// file, it is read here too.
 pub(crate) fn load_config_from_file(path: String) -> Result<Config, Error> {
-    let file_content = fs::read_to_string(path)?;
-    let config: Config = toml::from_str(&file_content)?;
+    let file_content =
+        fs::read_to_string(&path).with_context(|| format!("could not read {path}"))?;
+    let mut table: Table = toml::from_str(&file_content)?;
+
+    let overrides = apply_env_overrides(
+        &mut table,
+        env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }),
+    )?;
+
+    // Errors from deserializing the table name the offending key, regardless of
+    // whether it comes from the file or from the environment.
+    let mut config = deserialize_config(table, &overrides)?;
+    config.matrix.resolve_access_token()?;
+    if let Some(email) = &config.email {
+        email.validate()?;
+    }
+
     Ok(config)
 }
+
+/// A configuration key overridden with an environment variable.
+struct EnvOverride {
+    /// The name of the environment variable.
+    name: String,
+
+    /// The path to the key, starting from the root of the configuration.
+    keys: Vec<String>,
+
+    /// The value of the environment variable, before parsing it.
+    raw_value: String,
+}
+
+impl EnvOverride {
+    /// The path to the key, with a dot between nested keys.
+    fn path(&self) -> String {
+        self.keys.join(".")
+    }
+}
+
+/// Override configuration keys with the values of the environment variables
+/// named after them, and return the overridden keys.
+///
+/// The name of the variable is the path to the key, in upper case, with
+/// [`ENV_KEY_SEPARATOR`] between nested keys, and prefixed with [`ENV_PREFIX`].
+/// The value is parsed as a TOML value if possible (so that e.g. lists and
+/// numbers can be provided), and used as a string otherwise. Keys that expect a
+/// string get the value as is, see [`deserialize_config`].
+fn apply_env_overrides(
+    table: &mut Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Vec<EnvOverride>, Error> {
+    let mut overrides = Vec::new();
+
+    for (name, raw_value) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        let keys = path
+            .split(ENV_KEY_SEPARATOR)
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>();
+        if keys.iter().any(String::is_empty) {
+            bail!("{name}: invalid configuration key path");
+        }
+
+        // We know there's at least one key since `split` always yields at
+        // least one item, so unwrapping should not panic.
+        let (key, parents) = keys.split_last().unwrap();
+
+        let mut current = &mut *table;
+        for (idx, parent) in parents.iter().enumerate() {
+            let entry = current
+                .entry(parent.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+
+            current = match entry {
+                Value::Table(nested) => nested,
+                _ => bail!(
+                    "{name}: {} is not a section of the configuration",
+                    parents[..=idx].join(".")
+                ),
+            };
+        }
+
+        log::debug!(
+            "Overriding configuration key {} from {name}",
+            keys.join(".")
+        );
+        current.insert(key.clone(), parse_env_value(&raw_value));
+
+        overrides.push(EnvOverride {
+            name,
+            keys,
+            raw_value,
+        });
+    }
+
+    Ok(overrides)
+}
+
+/// Deserialize the configuration from the given table, in which the keys from
+/// the environment were overridden.
+///
+/// Since the values from the environment are parsed as TOML, a string that
+/// looks like e.g. a number (such as a numeric password) would be rejected by
+/// the key it's meant for. If a key from the environment fails to deserialize,
+/// it is given the value of the variable as a string instead. Variables that
+/// don't match any configuration key are warned about, since they're likely
+/// typos.
+fn deserialize_config(mut table: Table, overrides: &[EnvOverride]) -> Result<Config, Error> {
+    // The errors that led to using the raw values of keys, by key. If a key
+    // still fails with its raw value, the original error is more helpful.
+    let mut parse_errors = HashMap::new();
+
+    loop {
+        let mut ignored = Vec::new();
+        let mut track_ignored = |path: serde_ignored::Path| ignored.push(path.to_string());
+        let deserializer =
+            serde_ignored::Deserializer::new(Value::Table(table.clone()), &mut track_ignored);
+
+        let err = match serde_path_to_error::deserialize::<_, Config>(deserializer) {
+            Ok(config) => {
+                for env_override in overrides {
+                    let path = env_override.path();
+                    if ignored
+                        .iter()
+                        .any(|ignored| path == *ignored || path.starts_with(&format!("{ignored}.")))
+                    {
+                        log::warn!(
+                            "{}: {path} is not a configuration key, ignoring",
+                            env_override.name
+                        );
+                    }
+                }
+
+                return Ok(config);
+            }
+            Err(err) => err,
+        };
+
+        let failed_path = err.path().to_string();
+        if let Some(parse_error) = parse_errors.remove(&failed_path) {
+            return Err(parse_error);
+        }
+
+        let env_override = overrides
+            .iter()
+            .find(|env_override| env_override.path() == failed_path);
+
+        match env_override {
+            Some(env_override) if use_raw_env_value(&mut table, env_override) => {
+                parse_errors.insert(failed_path, Error::from(err.into_inner()));
+            }
+            _ => return Err(err.into_inner().into()),
+        }
+    }
+}
+
+/// Replace the parsed value of the given key from the environment with the
+/// value of the variable as a string. Returns `false` if the value already is
+/// a string.
+fn use_raw_env_value(table: &mut Table, env_override: &EnvOverride) -> bool {
+    // `apply_env_overrides` made sure the key exists and all of its parents are
+    // tables.
+    let (key, parents) = env_override.keys.split_last().unwrap();
+    let mut current = table;
+    for parent in parents {
+        let Some(Value::Table(nested)) = current.get_mut(parent) else {
+            return false;
+        };
+        current = nested;
+    }
+
+    match current.get_mut(key) {
+        Some(Value::String(_)) | None => false,
+        Some(value) => {
+            *value = Value::String(env_override.raw_value.clone());
+            true
+        }
+    }
+}
+
+/// Parse the value of an environment variable overriding a configuration key.
+fn parse_env_value(raw_value: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {raw_value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw_value.to_string()))
+}
+
+impl MatrixConfig {
+    /// Read the access token from `access_token_file` if it is set, and make
+    /// sure exactly one of `access_token` and `access_token_file` is set.
+    fn resolve_access_token(&mut self) -> Result<(), Error> {
+        match (&self.access_token_file, self.access_token.is_empty()) {
+            (Some(_), false) => {
+                bail!("matrix.access_token and matrix.access_token_file can't both be set")
+            }
+            (None, true) => {
+                bail!("matrix: either access_token or access_token_file must be set")
+            }
+            (None, false) => {}
+            (Some(path), true) => {
+                let token = fs::read_to_string(path)
+                    .with_context(|| format!("matrix.access_token_file: could not read {path}"))?;
+
+                self.access_token = token.trim().to_string();
+                if self.access_token.is_empty() {
+                    bail!("matrix.access_token_file: {path} is empty");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl EmailConfig {
+    /// Make sure the settings are consistent, i.e. credentials are either
+    /// complete or absent, and there is at least one recipient.
+    fn validate(&self) -> Result<(), Error> {
+        if self.username.is_some() != self.password.is_some() {
+            bail!("email.username and email.password must be set together");
+        }
+
+        if self.to.is_empty() {
+            bail!("email.to must include at least one recipient");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration with only the required keys set.
+    const MINIMAL_CONFIG: &str = r#"
+        push_message_substring = "mozilla-central"
+
+        [messages]
+        deps_out_of_date = "out of date"
+        deps_up_to_date = "up to date"
+        error_while_comparing_checksums = "error"
+
+        [matrix]
+        server_host = "matrix.example.com"
+        access_token = "secret"
+        room_id = "!room:example.com"
+    "#;
+
+    /// Load the minimal configuration with the given environment variables.
+    fn load_with_env(vars: &[(&str, &str)]) -> Result<Config, Error> {
+        let mut table: Table = toml::from_str(MINIMAL_CONFIG).unwrap();
+        let overrides = apply_env_overrides(
+            &mut table,
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )?;
+
+        deserialize_config(table, &overrides)
+    }
+
+    #[test]
+    fn parse_env_value_parses_toml() {
+        assert_eq!(parse_env_value("42"), Value::Integer(42));
+        assert_eq!(parse_env_value("true"), Value::Boolean(true));
+        assert_eq!(
+            parse_env_value(r#"["a", "b"]"#),
+            Value::Array(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(
+            parse_env_value(r#""quoted""#),
+            Value::String("quoted".into())
+        );
+    }
+
+    #[test]
+    fn parse_env_value_falls_back_to_string() {
+        assert_eq!(
+            parse_env_value("not a TOML value"),
+            Value::String("not a TOML value".into())
+        );
+        assert_eq!(parse_env_value(""), Value::String("".into()));
+    }
+
+    #[test]
+    fn env_overrides_nested_key() {
+        let cfg = load_with_env(&[
+            ("TBRUST_MATRIX__ROOM_ID", "!other:example.com"),
+            ("TBRUST_CHECK_TIMEOUT_SECONDS", "60"),
+            ("UNRELATED", "value"),
+        ])
+        .unwrap();
+
+        assert_eq!(cfg.matrix.room_id, "!other:example.com");
+        assert_eq!(cfg.check_timeout_seconds, 60);
+    }
+
+    #[test]
+    fn env_overrides_create_missing_section() {
+        let cfg = load_with_env(&[("TBRUST_HTTP__BIND_ADDRESS", "127.0.0.1:9090")]).unwrap();
+
+        let http = cfg.http.unwrap();
+        assert_eq!(http.bind_address, "127.0.0.1:9090");
+        assert_eq!(http.max_sync_age_seconds, default_max_sync_age_seconds());
+    }
+
+    #[test]
+    fn env_overrides_keep_strings_for_string_keys() {
+        let cfg = load_with_env(&[
+            ("TBRUST_MATRIX__ACCESS_TOKEN", "12345"),
+            ("TBRUST_PUSH_MESSAGE_SUBSTRING", "true"),
+        ])
+        .unwrap();
+
+        assert_eq!(cfg.matrix.access_token, "12345");
+        assert_eq!(cfg.push_message_substring, "true");
+    }
+
+    #[test]
+    fn env_overrides_report_original_error() {
+        let err = load_with_env(&[("TBRUST_CHECK_TIMEOUT_SECONDS", "-5")]).unwrap_err();
+        assert!(err.to_string().contains("-5"), "{err}");
+
+        let err = load_with_env(&[("TBRUST_CHECK_TIMEOUT_SECONDS", "soon")]).unwrap_err();
+        assert!(err.to_string().contains("soon"), "{err}");
+    }
+
+    #[test]
+    fn env_overrides_reject_invalid_paths() {
+        assert!(load_with_env(&[("TBRUST_MATRIX____ROOM_ID", "x")]).is_err());
+        assert!(load_with_env(&[("TBRUST_", "x")]).is_err());
+
+        let err = load_with_env(&[("TBRUST_PUSH_MESSAGE_SUBSTRING__KEY", "x")]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("push_message_substring is not a section"),
+            "{err}"
+        );
+    }
+
+    fn parse_schedule(
+        interval_minutes: Option<u64>,
+        cron: Option<&str>,
+    ) -> Result<Schedule, Error> {
+        Schedule::try_from(RawSchedule {
+            interval_minutes,
+            cron: cron.map(str::to_string),
+        })
+    }
+
+    fn utc(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn interval_schedule_is_due_after_the_interval() {
+        let schedule = parse_schedule(Some(90), None).unwrap();
+
+        assert_eq!(
+            schedule.next_after(utc("2025-01-01T01:30:00Z")),
+            Some(utc("2025-01-01T03:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_schedule_accepts_five_fields() {
+        let schedule = parse_schedule(None, Some("0 */6 * * *")).unwrap();
+
+        assert_eq!(
+            schedule.next_after(utc("2025-01-01T01:30:00Z")),
+            Some(utc("2025-01-01T06:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_schedule_accepts_seconds() {
+        let schedule = parse_schedule(None, Some("30 0 */6 * * *")).unwrap();
+
+        assert_eq!(
+            schedule.next_after(utc("2025-01-01T01:30:00Z")),
+            Some(utc("2025-01-01T06:00:30Z"))
+        );
+    }
+
+    #[test]
+    fn cron_schedule_numbers_days_from_sunday() {
+        // 2025-01-01 is a Wednesday, and 2 is Monday.
+        let schedule = parse_schedule(None, Some("0 9 * * 2")).unwrap();
+        assert_eq!(
+            schedule.next_after(utc("2025-01-01T00:00:00Z")),
+            Some(utc("2025-01-06T09:00:00Z"))
+        );
+
+        let schedule = parse_schedule(None, Some("0 9 * * Mon")).unwrap();
+        assert_eq!(
+            schedule.next_after(utc("2025-01-01T00:00:00Z")),
+            Some(utc("2025-01-06T09:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!(parse_schedule(Some(0), None).is_err());
+        assert!(parse_schedule(Some(60), Some("0 * * * *")).is_err());
+        assert!(parse_schedule(None, None).is_err());
+
+        let err = parse_schedule(None, Some("every day")).unwrap_err();
+        assert!(err.to_string().contains("\"every day\""), "{err}");
+    }
+}
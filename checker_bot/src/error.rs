@@ -0,0 +1,93 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::io;
+
+use reqwest::StatusCode;
+use thiserror::Error;
+
+/// Errors that cause the bot to exit.
+///
+/// Each variant maps to a distinct exit code (see [`Error::exit_code`]), taken
+/// from the BSD `sysexits.h` conventions, so that service managers can tell
+/// failures apart without parsing logs.
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    /// The configuration is missing, invalid, or doesn't match the state of the
+    /// Matrix account.
+    #[error("configuration error: {0:#}")]
+    Config(anyhow::Error),
+
+    /// The store could not be read or written.
+    #[error("store error: {0}")]
+    Store(io::Error),
+
+    /// The Matrix server rejected the configured access token.
+    #[error("Matrix authentication error: {0}")]
+    MatrixAuth(checker_base::error::Error),
+
+    /// The Matrix server could not be reached, or responded with an unexpected
+    /// error.
+    #[error("Matrix error: {0}")]
+    Matrix(checker_base::error::Error),
+
+    /// An email could not be sent.
+    #[error("email error: {0:#}")]
+    Email(anyhow::Error),
+
+    /// An I/O error that the bot can't recover from.
+    #[error("I/O error while {context}: {source}")]
+    Io {
+        context: &'static str,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    /// The code the process should exit with when this error happens.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            // EX_CONFIG
+            Error::Config(_) => 78,
+            // EX_CANTCREAT
+            Error::Store(_) => 73,
+            // EX_NOPERM
+            Error::MatrixAuth(_) => 77,
+            // EX_UNAVAILABLE
+            Error::Matrix(_) | Error::Email(_) => 69,
+            // EX_IOERR
+            Error::Io { .. } => 74,
+        }
+    }
+
+    /// Classify an error that stopped the bot from serving its Matrix room.
+    ///
+    /// While serving the room, the only I/O the bot does is reading and
+    /// writing the files in its store, so I/O errors are store errors. A
+    /// request being rejected as unauthorized is only an authentication error
+    /// if it was made to the Matrix server, rather than e.g. to the Mercurial
+    /// server.
+    pub fn from_matrix(err: checker_base::error::Error) -> Self {
+        match err {
+            checker_base::error::Error::Io(source) => Error::Store(source),
+            checker_base::error::Error::Network(ref network_err)
+                if is_matrix_request(network_err)
+                    && matches!(
+                        network_err.status(),
+                        Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+                    ) =>
+            {
+                Error::MatrixAuth(err)
+            }
+            _ => Error::Matrix(err),
+        }
+    }
+}
+
+/// Whether the request that failed with the given error was made to the Matrix
+/// client-server API.
+fn is_matrix_request(err: &reqwest::Error) -> bool {
+    err.url()
+        .is_some_and(|url| url.path().starts_with("/_matrix/"))
+}
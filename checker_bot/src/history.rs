@@ -0,0 +1,181 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use chrono::DateTime;
+
+use crate::storage::{CheckOutcome, CheckRecord, Trigger};
+
+/// A change in whether the comm-central dependencies are in sync with
+/// mozilla-central.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SyncChange {
+    /// The dependencies went out of sync at the given time.
+    Broke { timestamp: u64 },
+
+    /// The dependencies were brought back in sync at the given time, after
+    /// having been out of sync since `since`.
+    Restored { timestamp: u64, since: u64 },
+}
+
+/// Figure out when the dependencies went out of sync, and when they were
+/// brought back in sync, from the given check records (oldest first).
+///
+/// Checks that failed with an error are ignored, since they don't tell us
+/// anything about the state of the dependencies.
+pub(crate) fn sync_changes(records: &[CheckRecord]) -> Vec<SyncChange> {
+    let mut changes = Vec::new();
+    let mut out_of_sync_since: Option<u64> = None;
+    let mut previous: Option<CheckOutcome> = None;
+
+    for record in records {
+        match (previous, record.outcome) {
+            (_, CheckOutcome::Error) => continue,
+            (Some(CheckOutcome::UpToDate), CheckOutcome::OutOfDate) => {
+                out_of_sync_since = Some(record.timestamp);
+                changes.push(SyncChange::Broke {
+                    timestamp: record.timestamp,
+                });
+            }
+            (None, CheckOutcome::OutOfDate) => {
+                // We don't know when the dependencies went out of sync, so the
+                // best we can do is assume it was at the first check.
+                out_of_sync_since = Some(record.timestamp);
+            }
+            (Some(CheckOutcome::OutOfDate), CheckOutcome::UpToDate) => {
+                changes.push(SyncChange::Restored {
+                    timestamp: record.timestamp,
+                    // `out_of_sync_since` is always set when the previous
+                    // outcome was "out of date".
+                    since: out_of_sync_since.take().unwrap_or(record.timestamp),
+                });
+            }
+            _ => {}
+        }
+
+        previous = Some(record.outcome);
+    }
+
+    changes
+}
+
+/// Format a UNIX timestamp (in seconds) as a human-readable UTC date and time.
+pub(crate) fn format_timestamp(timestamp: u64) -> String {
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(datetime) => datetime.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Format a duration (in seconds) in a human-readable way, e.g. "2d 3h 15m".
+pub(crate) fn format_duration(seconds: u64) -> String {
+    let days = seconds / 86400;
+    let hours = (seconds % 86400) / 3600;
+    let minutes = (seconds % 3600) / 60;
+
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+/// Format a single check record on one line.
+fn format_check(record: &CheckRecord) -> String {
+    let outcome = match record.outcome {
+        CheckOutcome::UpToDate => "up to date".to_string(),
+        CheckOutcome::OutOfDate => {
+            let mismatched = record
+                .files
+                .iter()
+                .filter(|file| !file.matches())
+                .map(|file| file.path.as_str())
+                .collect::<Vec<_>>();
+
+            match mismatched.is_empty() {
+                true => "out of date".to_string(),
+                false => format!("out of date ({})", mismatched.join(", ")),
+            }
+        }
+        CheckOutcome::Error => format!(
+            "error ({})",
+            record.error.as_deref().unwrap_or("unknown error")
+        ),
+    };
+
+    let trigger = match &record.trigger {
+        Trigger::PushNotice { event_id } => match event_id {
+            Some(event_id) => format!("push notice {event_id}"),
+            None => "push notice".to_string(),
+        },
+        Trigger::CommPushNotice { event_id } => format!("comm-central push notice {event_id}"),
+        Trigger::Schedule => "the schedule".to_string(),
+        Trigger::HttpRequest => "HTTP request".to_string(),
+        Trigger::PulsePush { push_id } => format!("Pulse push {push_id}"),
+    };
+
+    format!(
+        "{}: {} - mozilla-central {}, comm-central {}, took {:.1}s, triggered by {}",
+        format_timestamp(record.timestamp),
+        outcome,
+        record.moz_rev,
+        record.tb_rev,
+        record.duration_ms as f64 / 1000.0,
+        trigger,
+    )
+}
+
+/// Format the most recent check records (up to the given limit), most recent
+/// first.
+pub(crate) fn format_checks(records: &[CheckRecord], limit: usize) -> String {
+    if records.is_empty() {
+        return "No check recorded yet.".to_string();
+    }
+
+    records
+        .iter()
+        .rev()
+        .take(limit)
+        .map(format_check)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Format the most recent changes in the sync status of the dependencies (up
+/// to the given limit), most recent first.
+pub(crate) fn format_sync_changes(records: &[CheckRecord], limit: usize) -> String {
+    let changes = sync_changes(records);
+    if changes.is_empty() {
+        return "No change in sync status recorded yet.".to_string();
+    }
+
+    changes
+        .iter()
+        .rev()
+        .take(limit)
+        .map(|change| match change {
+            SyncChange::Broke { timestamp } => {
+                format!(
+                    "{}: dependencies went out of sync",
+                    format_timestamp(*timestamp)
+                )
+            }
+            SyncChange::Restored { timestamp, since } => format!(
+                "{}: dependencies back in sync after {}",
+                format_timestamp(*timestamp),
+                format_duration(timestamp.saturating_sub(*since)),
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Format a full history report, with both the most recent checks and the most
+/// recent changes in sync status.
+pub(crate) fn format_history(records: &[CheckRecord], limit: usize) -> String {
+    format!(
+        "Recent checks:\n{}\n\nSync status changes:\n{}",
+        format_checks(records, limit),
+        format_sync_changes(records, limit),
+    )
+}
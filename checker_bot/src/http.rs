@@ -0,0 +1,265 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::io::Error;
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use crate::config::{Config, default_max_sync_age_seconds};
+use crate::metrics::METRICS;
+use crate::queue::CheckQueue;
+use crate::storage::{CheckRequest, SharedStore, check_store_writable, now_ts};
+
+/// The content type of the Prometheus text format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The state shared by the HTTP handlers.
+struct AppState {
+    /// The current configuration, updated each time it is reloaded.
+    config: watch::Receiver<Config>,
+    store: SharedStore,
+    queue: CheckQueue,
+}
+
+/// The body of a request to `/trigger`.
+#[derive(Debug, Deserialize)]
+struct TriggerRequest {
+    moz_rev: String,
+    comm_rev: Option<String>,
+}
+
+/// Serve the bot's HTTP endpoints on the given listener.
+///
+/// The endpoints are:
+///
+/// * `/healthz`, which responds with a 200 status code if the bot is healthy,
+///   and a 503 status code (with the reason in the body) otherwise.
+/// * `/metrics`, which responds with the bot's metrics in the Prometheus text
+///   format.
+/// * `POST /trigger`, which queues a check of the given revisions and responds
+///   with its ID, and `GET /checks/{id}`, which responds with the status of the
+///   check with the given ID. These require the configured trigger token as a
+///   bearer token, and respond with a 404 status code if none is configured.
+///
+/// The handlers read the configuration from the given receiver, so they pick up
+/// a reloaded trigger token or sync age without a restart.
+pub(crate) async fn serve(
+    config: watch::Receiver<Config>,
+    store: SharedStore,
+    queue: CheckQueue,
+    listener: TcpListener,
+) -> Result<(), Error> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(metrics))
+        .route("/trigger", post(trigger))
+        .route("/checks/{id}", get(check_status))
+        .with_state(Arc::new(AppState {
+            config,
+            store,
+            queue,
+        }));
+
+    axum::serve(listener, app).await
+}
+
+/// Check whether the bot is healthy, i.e. it has successfully synced recently,
+/// and its store is writable.
+async fn healthz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    // Don't hold the borrow across the store check below, which awaits.
+    let cfg = state.config.borrow().clone();
+
+    // The HTTP section might have been removed by a reload, in which case the
+    // server keeps running until the next restart.
+    let max_sync_age = cfg
+        .http
+        .as_ref()
+        .map_or_else(default_max_sync_age_seconds, |http| {
+            http.max_sync_age_seconds
+        });
+
+    match METRICS.last_successful_sync() {
+        None => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no successful sync yet".to_string(),
+            );
+        }
+        Some(last_sync) if now_ts().saturating_sub(last_sync) > max_sync_age => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!(
+                    "last successful sync was {} seconds ago",
+                    now_ts().saturating_sub(last_sync)
+                ),
+            );
+        }
+        Some(_) => {}
+    }
+
+    if let Err(err) = check_store_writable(&cfg).await {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("store is not writable: {err}"),
+        );
+    }
+
+    (StatusCode::OK, "ok".to_string())
+}
+
+/// Render the bot's metrics.
+async fn metrics() -> impl IntoResponse {
+    ([(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)], METRICS.render())
+}
+
+/// Queue a check of the revisions in the request body.
+///
+/// Responds with a 202 status code and the ID of the check, or with a 503
+/// status code if the check queue is full.
+async fn trigger(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<TriggerRequest>,
+) -> Response {
+    if let Some(response) = reject_unauthenticated(&state.config.borrow(), &headers) {
+        return response;
+    }
+
+    // Revisions end up in URLs, so only accept the characters that can appear
+    // in a revision or a symbolic name.
+    let revisions = [Some(&request.moz_rev), request.comm_rev.as_ref()];
+    if revisions
+        .into_iter()
+        .flatten()
+        .any(|rev| !is_valid_rev(rev))
+    {
+        return error_response(StatusCode::BAD_REQUEST, "invalid revision");
+    }
+
+    match state
+        .queue
+        .enqueue_manual(request.moz_rev, request.comm_rev)
+        .await
+    {
+        Ok(Some(id)) => {
+            log::info!("Queued check {id} requested over HTTP");
+            (StatusCode::ACCEPTED, Json(json!({ "id": id }))).into_response()
+        }
+        Ok(None) => error_response(StatusCode::SERVICE_UNAVAILABLE, "the check queue is full"),
+        Err(err) => {
+            log::error!("Error while queueing check requested over HTTP: {err}");
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not queue the check",
+            )
+        }
+    }
+}
+
+/// Respond with the status of the check with the given ID: `pending` if it
+/// hasn't completed yet, or `completed` along with its result.
+async fn check_status(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Response {
+    if let Some(response) = reject_unauthenticated(&state.config.borrow(), &headers) {
+        return response;
+    }
+
+    let store = state.store.lock().await;
+    let store_state = store.state();
+
+    let pending = store_state
+        .check_queue
+        .iter()
+        .find_map(|request| match request {
+            CheckRequest::Manual(check) if check.id == id => Some(check),
+            _ => None,
+        });
+    if let Some(check) = pending {
+        return Json(json!({
+            "id": id,
+            "status": "pending",
+            "moz_rev": check.moz_rev,
+            "comm_rev": check.tb_rev,
+        }))
+        .into_response();
+    }
+
+    let record = store_state
+        .check_history
+        .iter()
+        .rfind(|record| record.id == Some(id));
+    match record {
+        Some(record) => Json(json!({
+            "id": id,
+            "status": "completed",
+            "result": record,
+        }))
+        .into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "unknown check"),
+    }
+}
+
+/// The response to send if the request doesn't include the configured trigger
+/// token as a bearer token, or if no token is configured.
+fn reject_unauthenticated(cfg: &Config, headers: &HeaderMap) -> Option<Response> {
+    let Some(expected) = cfg
+        .http
+        .as_ref()
+        .and_then(|http| http.trigger_token.as_deref())
+    else {
+        return Some(error_response(StatusCode::NOT_FOUND, "not found"));
+    };
+
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if constant_time_eq(expected, provided) => None,
+        _ => Some(unauthenticated_response()),
+    }
+}
+
+/// The response to a request that doesn't include the trigger token.
+fn unauthenticated_response() -> Response {
+    error_response(StatusCode::UNAUTHORIZED, "missing or invalid token")
+}
+
+/// Compare two strings in a time that only depends on their length, so the
+/// token can't be guessed by timing responses.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Whether the given string looks like a Mercurial revision, i.e. a hash or a
+/// symbolic name such as `tip`.
+fn is_valid_rev(rev: &str) -> bool {
+    !rev.is_empty()
+        && rev
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// A JSON error response with the given status code.
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
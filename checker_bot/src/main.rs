@@ -2,49 +2,226 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::io::ErrorKind;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use clap::Parser;
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
 use env_logger::Env;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
 
-use crate::config::load_config_from_file;
-use crate::storage::read_sync_token_from_store;
+use checker_base::checksums::FileCheckResult;
+
+use crate::config::{Config, load_config_from_file};
+use crate::error::Error;
+use crate::history::format_history;
+use crate::matrix::check_access;
+use crate::notifier::CheckReport;
+use crate::notifier::email::EmailNotifier;
+use crate::queue::CheckQueue;
+use crate::signals::SignalHandler;
+use crate::storage::{CheckOutcome, Store, Trigger, check_store_writable, now_ts};
+use crate::template::CheckDetails;
 
 mod config;
+mod error;
+mod history;
+mod http;
 mod matrix;
+mod metrics;
+mod notifier;
+mod pulse;
+mod queue;
+mod signals;
 mod storage;
+mod template;
 
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long)]
     config_file: String,
+
+    /// What to do. If omitted, the bot starts serving its Matrix room.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that the configuration is valid and that the bot is ready to
+    /// serve its Matrix room, without starting it.
+    CheckConfig,
+
+    /// Print the history of past checks and changes in sync status.
+    History {
+        /// The maximum number of checks and sync status changes to print.
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Send a sample email reporting the dependencies out of date, using the
+    /// `[email]` section of the configuration.
+    TestEmail,
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let args = Args::parse();
 
     // Default the log level to "info".
     let env = Env::default().default_filter_or("info");
     env_logger::init_from_env(env);
 
-    let cfg = match load_config_from_file(args.config_file) {
-        Ok(cfg) => cfg,
-        Err(err) => panic!("error parsing config: {err:?}"),
-    };
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+/// Run the command given on the command line, or start serving the Matrix room
+/// if there isn't one.
+async fn run(args: Args) -> Result<(), Error> {
+    let cfg = load_config_from_file(args.config_file.clone()).map_err(Error::Config)?;
 
     log::info!("Parsed config");
 
-    let sync_token = match read_sync_token_from_store(&cfg).await {
-        Ok(token) => token,
-        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
-        Err(err) => panic!("error reading sync token from storage: {err:?}"),
-    };
+    if let Some(Command::CheckConfig) = args.command {
+        return check_config(&cfg).await;
+    }
+
+    if let Some(Command::TestEmail) = args.command {
+        return send_test_email(&cfg).await;
+    }
+
+    // Printing the history shouldn't change the store, since the bot might be
+    // running.
+    if let Some(Command::History { limit }) = args.command {
+        let state = Store::load_state(&cfg).await.map_err(Error::Store)?;
+        println!("{}", format_history(&state.check_history, limit));
+        return Ok(());
+    }
+
+    let store = Store::open(&cfg).await.map_err(Error::Store)?;
+
+    log::info!("Opened store");
+
+    let store = Arc::new(Mutex::new(store));
+    let (queue, requests) = CheckQueue::new(store.clone()).await;
+
+    let (signal_handler, config, shutdown) = SignalHandler::install(args.config_file, cfg.clone())
+        .map_err(|source| Error::Io {
+            context: "installing signal handlers",
+            source,
+        })?;
+    tokio::spawn(signal_handler.run());
+
+    if let Some(http_cfg) = &cfg.http {
+        let listener = TcpListener::bind(&http_cfg.bind_address)
+            .await
+            .map_err(|source| Error::Io {
+                context: "binding the HTTP listener",
+                source,
+            })?;
 
-    log::info!("Read stored sync token (if any)");
+        log::info!("Serving HTTP endpoints on {}", http_cfg.bind_address);
 
-    match matrix::sync(&cfg, sync_token).await {
-        Ok(_) => {}
-        Err(err) => panic!("sync loop exited with error: {err:?}"),
+        let config = config.clone();
+        let store = store.clone();
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            if let Err(err) = http::serve(config, store, queue, listener).await {
+                log::error!("HTTP server exited with error: {err}");
+            }
+        });
     }
+
+    if let Some(pulse_cfg) = cfg.pulse.clone() {
+        tokio::spawn(pulse::consume(pulse_cfg, queue.clone()));
+    }
+
+    matrix::sync(config, store, queue, requests, shutdown)
+        .await
+        .map_err(Error::from_matrix)?;
+
+    log::info!("Shut down cleanly");
+
+    Ok(())
+}
+
+/// Check that the bot is ready to serve its Matrix room: its store is writable,
+/// its access token is valid, and it is a member of the configured room.
+async fn check_config(cfg: &Config) -> Result<(), Error> {
+    check_store_writable(cfg).await.map_err(Error::Store)?;
+    println!("Store directory is writable");
+
+    let access = check_access(&cfg.matrix)
+        .await
+        .map_err(Error::from_matrix)?;
+    println!("Access token belongs to {}", access.user_id);
+
+    if !access.in_room {
+        return Err(Error::Config(anyhow!(
+            "{} is not a member of room {}",
+            access.user_id,
+            cfg.matrix.room_id
+        )));
+    }
+    println!(
+        "{} is a member of room {}",
+        access.user_id, cfg.matrix.room_id
+    );
+
+    println!("Configuration OK");
+
+    Ok(())
+}
+
+/// Send an email about a made-up check that found the dependencies out of date,
+/// so the email settings can be tested without waiting for an actual change.
+async fn send_test_email(cfg: &Config) -> Result<(), Error> {
+    let Some(email_cfg) = &cfg.email else {
+        return Err(Error::Config(anyhow!(
+            "the configuration has no [email] section"
+        )));
+    };
+
+    let files = [FileCheckResult {
+        path: "Cargo.lock".to_string(),
+        expected_checksum: "0".repeat(64),
+        actual_checksum: "f".repeat(64),
+        fetch_duration_ms: 0,
+        size: 0,
+        content_type: None,
+    }];
+    let details = CheckDetails {
+        moz_rev: "0123456789ab".to_string(),
+        comm_rev: "ba9876543210".to_string(),
+        mismatched_files: vec!["Cargo.lock".to_string()],
+        ..Default::default()
+    };
+    let report = CheckReport {
+        timestamp: now_ts(),
+        trigger: &Trigger::Schedule,
+        outcome: CheckOutcome::OutOfDate,
+        files: &files,
+        moz_changeset: None,
+        tb_changeset: None,
+        details: &details,
+        incident: None,
+        back_in_sync: false,
+        notable: true,
+    };
+
+    EmailNotifier::new(email_cfg, &cfg.messages)
+        .send(&report)
+        .await
+        .map_err(Error::Email)?;
+
+    println!("Test email sent");
+
+    Ok(())
 }
@@ -2,12 +2,27 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
+use checker_base::error::Error;
+
+use crate::config::MatrixConfig;
+
 pub(crate) use sync::sync;
 
+mod account;
+mod check;
+mod commands;
+#[cfg(feature = "e2ee")]
+mod crypto;
+mod escalation;
+mod membership;
+mod notifier;
+mod schedule;
 mod send;
 mod sync;
+mod worker;
 
 /// The `msgtype` property of an `m.room.message` Matrix event content.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,9 +30,13 @@ enum MessageType {
     #[serde(rename = "m.notice")]
     Notice,
 
-    // We only care about notices (because that's what we read and send). We
-    // lump everything else in a catch-all variant so serde doesn't complain
-    // about not being able to find one that fits.
+    #[serde(rename = "m.text")]
+    Text,
+
+    // We only care about notices (because that's what we read and send) and
+    // text messages (because that's how users send us commands). We lump
+    // everything else in a catch-all variant so serde doesn't complain about
+    // not being able to find one that fits.
     #[serde(other)]
     Unsupported,
 }
@@ -27,4 +46,74 @@ enum MessageType {
 struct MessageEventContent {
     pub body: Option<String>,
     pub msgtype: Option<MessageType>,
+
+    /// The format of `formatted_body`, which is always `org.matrix.custom.html`
+    /// for the messages we send.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+
+    /// An HTML version of `body`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_body: Option<String>,
+
+    /// The event this message relates to, e.g. because it is a reply to it.
+    /// We only ever set this on messages we send, so there's no need to
+    /// deserialize it (and risk failing on relation types we don't support).
+    #[serde(
+        rename = "m.relates_to",
+        skip_deserializing,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub relates_to: Option<RelatesTo>,
+
+    /// The users mentioned in this message. Like `relates_to`, this is only
+    /// ever set on messages we send.
+    #[serde(
+        rename = "m.mentions",
+        skip_deserializing,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mentions: Option<Mentions>,
+}
+
+/// The `m.mentions` property of an event content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Mentions {
+    pub user_ids: Vec<String>,
+}
+
+/// The `m.relates_to` property of an event content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RelatesTo {
+    #[serde(rename = "m.in_reply_to")]
+    pub in_reply_to: InReplyTo,
+}
+
+/// The event a message replies to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InReplyTo {
+    pub event_id: String,
+}
+
+/// The result of checking the bot's access to the Matrix server.
+#[derive(Debug, Clone)]
+pub(crate) struct AccessCheck {
+    /// The ID of the user the configured access token belongs to.
+    pub user_id: String,
+
+    /// Whether the user is a member of the configured room.
+    pub in_room: bool,
+}
+
+/// Check that the configured access token is valid, and whether the bot is a
+/// member of the configured room.
+pub(crate) async fn check_access(matrix_cfg: &MatrixConfig) -> Result<AccessCheck, Error> {
+    let client = Client::new();
+
+    let user_id = account::whoami(matrix_cfg, client.clone()).await?.user_id;
+    let in_room = membership::joined_rooms(matrix_cfg, client)
+        .await?
+        .contains(&matrix_cfg.room_id);
+
+    Ok(AccessCheck { user_id, in_room })
 }
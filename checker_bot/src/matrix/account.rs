@@ -0,0 +1,45 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+
+use checker_base::error::Error;
+
+use crate::config::MatrixConfig;
+
+/// A response to a `/account/whoami` request.
+///
+/// See <https://spec.matrix.org/v1.14/client-server-api/#get_matrixclientv3accountwhoami>
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct WhoAmIResponse {
+    pub user_id: String,
+    #[cfg(feature = "e2ee")]
+    pub device_id: Option<String>,
+}
+
+/// Retrieve the Matrix user ID of the account the configured access token
+/// belongs to.
+pub(super) async fn whoami(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+) -> Result<WhoAmIResponse, Error> {
+    let url = format!(
+        "https://{}/_matrix/client/v3/account/whoami",
+        matrix_cfg.server_host
+    );
+    let url = Url::parse(&url)?;
+
+    let response = client
+        .get(url)
+        .bearer_auth(&matrix_cfg.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(response)
+}
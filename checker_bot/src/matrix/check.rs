@@ -0,0 +1,376 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use tokio::time;
+
+use checker_base::checksums::{ChangeSet, checksums_file_digest, verify_checksums_match};
+use checker_base::error::Error;
+use checker_base::lockfile::changed_crates;
+
+use crate::config::Config;
+use crate::matrix::notifier::MatrixNotifier;
+use crate::metrics::METRICS;
+use crate::notifier::email::EmailNotifier;
+use crate::notifier::webhook::WebhookNotifier;
+use crate::notifier::{CheckReport, Notifier};
+use crate::storage::{CheckOutcome, CheckRecord, SharedStore, Trigger, now_ts};
+use crate::template::CheckDetails;
+
+/// Download the relevant files from mozilla-central and compare their checksums
+/// to the ones stored in comm-central, then tell the configured notifiers about
+/// the result and record it in the store.
+///
+/// Checks triggered by a mozilla-central push (whether announced in the Matrix
+/// room or on Pulse) are always notable, i.e. result in a message to the Matrix
+/// room. Checks triggered by a comm-central push are only meant to find out
+/// whether the dependencies are back in sync, so they're only notable if they
+/// are. Scheduled checks are a safety net for missed pushes, so they're only
+/// notable if the outcome differs from the one of the previous check that
+/// didn't fail, or if they fail after a check that didn't. Checks
+/// requested over HTTP are always notable, but they can be for any revision, so
+/// they're reported with their own message, and don't affect the sync status of
+/// the dependencies.
+///
+/// `id` is the ID to record the check with, if one was already given out for
+/// it, otherwise a new one is given out before the check starts. `push_ids` are the IDs of the pushes the check covers, oldest first.
+///
+/// If the check takes longer than the configured timeout, it is abandoned and
+/// recorded as an error.
+pub(super) async fn run_check(
+    cfg: &Config,
+    client: Client,
+    store: &SharedStore,
+    trigger: Trigger,
+    change_set: ChangeSet,
+    id: Option<u64>,
+    push_ids: Vec<String>,
+) -> Result<(), Error> {
+    // Give out the ID up front, so that it can be included in messages.
+    let id = match id {
+        Some(id) => id,
+        None => store.lock().await.next_check_id(),
+    };
+
+    let timeout = Duration::from_secs(cfg.check_timeout_seconds);
+    let check = perform_check(
+        cfg,
+        client,
+        store,
+        trigger.clone(),
+        change_set.clone(),
+        id,
+        push_ids,
+    );
+
+    let Ok(result) = time::timeout(timeout, check).await else {
+        log::error!("Check timed out after {}s", timeout.as_secs());
+        METRICS.record_check(CheckOutcome::Error);
+
+        store
+            .lock()
+            .await
+            .record_check(CheckRecord {
+                id: Some(id),
+                timestamp: now_ts(),
+                trigger,
+                moz_rev: change_set.moz_rev().to_string(),
+                tb_rev: change_set.tb_rev().to_string(),
+                moz_changeset: None,
+                tb_changeset: None,
+                files: vec![],
+                duration_ms: timeout.as_millis() as u64,
+                outcome: CheckOutcome::Error,
+                error: Some(format!("timed out after {}s", timeout.as_secs())),
+                notice_event_id: None,
+            })
+            .await?;
+
+        return Ok(());
+    };
+
+    result
+}
+
+/// Perform a check and record its result, see [`run_check`].
+async fn perform_check(
+    cfg: &Config,
+    client: Client,
+    store: &SharedStore,
+    trigger: Trigger,
+    change_set: ChangeSet,
+    id: u64,
+    push_ids: Vec<String>,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let result = verify_checksums_match(change_set.clone()).await;
+    let duration = start.elapsed();
+
+    let (outcome, files, changesets, checksums_digest, error) = match result {
+        Ok(report) => {
+            let outcome = match report.checksums_match() {
+                true => {
+                    log::info!("Checksums match");
+                    CheckOutcome::UpToDate
+                }
+                false => {
+                    log::info!("Checksums do not match");
+                    CheckOutcome::OutOfDate
+                }
+            };
+            (
+                outcome,
+                report.files,
+                Some((report.moz_rev, report.tb_rev)),
+                Some(report.checksums_digest),
+                None,
+            )
+        }
+        Err(err) => {
+            log::error!("Error while comparing checksums: {err}");
+            (
+                CheckOutcome::Error,
+                vec![],
+                None,
+                None,
+                Some(err.to_string()),
+            )
+        }
+    };
+    let (moz_changeset, tb_changeset) = changesets.unzip();
+
+    // Use the changesets the files were fetched at for the rest of the check,
+    // if the revisions could be resolved.
+    let change_set = match (&moz_changeset, &tb_changeset) {
+        (Some(moz), Some(tb)) => ChangeSet {
+            moz_rev: Some(moz.node.clone()),
+            tb_rev: Some(tb.node.clone()),
+            ..change_set
+        },
+        _ => change_set,
+    };
+
+    METRICS.record_check(outcome);
+    for file in &files {
+        METRICS.record_hg_fetch(&file.path, Duration::from_millis(file.fetch_duration_ms));
+    }
+
+    // Only bother figuring out which crates changed if they're out of sync.
+    let changed_crates = match outcome {
+        CheckOutcome::OutOfDate => match changed_crates(&change_set).await {
+            Ok(crates) => Some(crates.iter().map(ToString::to_string).collect()),
+            Err(err) => {
+                log::error!("Error while listing changed crates: {err}");
+                None
+            }
+        },
+        _ => None,
+    };
+
+    // The incident the dependencies were out of sync in before this check, if
+    // any, and the outcome of the previous check of the repository tips, as
+    // well as of the previous one that completed.
+    let (incident, previous_outcome, previous_completed_outcome) = {
+        let store = store.lock().await;
+        let state = store.state();
+        let mut tracked = state
+            .check_history
+            .iter()
+            .rev()
+            .filter(|record| record.trigger.tracks_sync_status());
+        (
+            state.incident.clone(),
+            tracked.clone().next().map(|record| record.outcome),
+            tracked
+                .find(|record| record.outcome != CheckOutcome::Error)
+                .map(|record| record.outcome),
+        )
+    };
+
+    let details = CheckDetails {
+        moz_rev: change_set.moz_rev().to_string(),
+        comm_rev: change_set.tb_rev().to_string(),
+        mismatched_files: files
+            .iter()
+            .filter(|file| !file.matches())
+            .map(|file| file.path.clone())
+            .collect(),
+        changed_crates,
+        push_id: push_ids.last().cloned(),
+        push_ids,
+        error: error.clone(),
+        duration,
+        out_of_sync_for: incident
+            .as_ref()
+            .map(|incident| Duration::from_secs(now_ts().saturating_sub(incident.since))),
+        check_id: Some(id),
+        outcome: Some(outcome),
+    };
+
+    let back_in_sync =
+        trigger.tracks_sync_status() && outcome == CheckOutcome::UpToDate && incident.is_some();
+
+    let notable = match trigger {
+        Trigger::PushNotice { .. } => true,
+        Trigger::CommPushNotice { .. } => back_in_sync,
+        // An error in between two checks with the same outcome doesn't mean the
+        // sync status changed, and only the first of a series of errors is
+        // worth reporting.
+        Trigger::Schedule => match outcome {
+            CheckOutcome::Error => previous_outcome != Some(CheckOutcome::Error),
+            _ => previous_completed_outcome != Some(outcome),
+        },
+        Trigger::HttpRequest => true,
+        Trigger::PulsePush { .. } => true,
+    };
+
+    let timestamp = now_ts();
+    let report = CheckReport {
+        timestamp,
+        trigger: &trigger,
+        outcome,
+        files: &files,
+        moz_changeset: moz_changeset.as_ref(),
+        tb_changeset: tb_changeset.as_ref(),
+        details: &details,
+        incident: incident.as_ref(),
+        back_in_sync,
+        notable,
+    };
+
+    // A notifier failing shouldn't prevent the others from being notified, or
+    // the check from being recorded.
+    let mut notice_event_id = None;
+    for notifier in notifiers(cfg, client) {
+        match notifier.notify(&report).await {
+            Ok(event_id) => notice_event_id = notice_event_id.or(event_id),
+            Err(err) => log::error!("Error while notifying {}: {err}", notifier.name()),
+        }
+    }
+
+    let tracks_sync_status = trigger.tracks_sync_status();
+    let mut store = store.lock().await;
+    store
+        .record_check(CheckRecord {
+            id: Some(id),
+            timestamp,
+            trigger,
+            moz_rev: change_set.moz_rev().to_string(),
+            tb_rev: change_set.tb_rev().to_string(),
+            moz_changeset,
+            tb_changeset,
+            files,
+            duration_ms: duration.as_millis() as u64,
+            outcome,
+            error,
+            notice_event_id,
+        })
+        .await?;
+
+    // Remember the checksums the dependencies are out of sync with, so that
+    // comm-central pushes that don't change them can be ignored.
+    if let Some(digest) = checksums_digest
+        && tracks_sync_status
+        && store.state().incident.is_some()
+    {
+        store
+            .update(|state| {
+                if let Some(incident) = &mut state.incident {
+                    incident.checksums_digest = Some(digest);
+                }
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Process a comm-central push.
+///
+/// If the dependencies are out of sync, and the push changed the checksums
+/// stored in comm-central (e.g. because it includes a revendor), check whether
+/// they're back in sync. Otherwise, there's nothing to do.
+pub(super) async fn process_comm_push(
+    cfg: &Config,
+    client: Client,
+    store: &SharedStore,
+    event_id: &str,
+    body: &str,
+) -> Result<(), Error> {
+    let Some(incident) = store.lock().await.state().incident.clone() else {
+        log::debug!("Ignoring comm-central push since the dependencies are in sync");
+        return Ok(());
+    };
+
+    // Failing to download the checksums file is likely to be transient, and
+    // we'll have another chance on the next push, so don't fail over it.
+    let timeout = Duration::from_secs(cfg.check_timeout_seconds);
+    let change_set = cfg.change_set(None, None);
+    let digest = match time::timeout(timeout, checksums_file_digest(&change_set)).await {
+        Ok(Ok(digest)) => digest,
+        Ok(Err(err)) => {
+            log::error!("Error while downloading comm-central checksums: {err}");
+            return Ok(());
+        }
+        Err(_) => {
+            log::error!(
+                "Timed out after {}s while downloading comm-central checksums",
+                timeout.as_secs()
+            );
+            return Ok(());
+        }
+    };
+
+    if incident.checksums_digest.as_ref() == Some(&digest) {
+        log::debug!("Ignoring comm-central push since the checksums didn't change");
+        return Ok(());
+    }
+
+    log::info!("Processing comm-central push that changed the checksums");
+
+    let trigger = Trigger::CommPushNotice {
+        event_id: event_id.to_string(),
+    };
+    run_check(
+        cfg,
+        client,
+        store,
+        trigger,
+        change_set,
+        None,
+        extract_push_ids(cfg, &[body]),
+    )
+    .await
+}
+
+/// Extract the push IDs from the given push notice bodies, using the pattern
+/// from the configuration if there is one.
+pub(super) fn extract_push_ids(cfg: &Config, bodies: &[&str]) -> Vec<String> {
+    match &cfg.push_id_pattern {
+        Some(pattern) => bodies
+            .iter()
+            .filter_map(|body| pattern.extract(body))
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// The notifiers to tell about the result of a check: the Matrix room, then
+/// each configured webhook, then email if configured.
+fn notifiers(cfg: &Config, client: Client) -> Vec<Box<dyn Notifier + '_>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> =
+        vec![Box::new(MatrixNotifier::new(cfg, client.clone()))];
+
+    for webhook in &cfg.webhooks {
+        notifiers.push(Box::new(WebhookNotifier::new(webhook, client.clone())));
+    }
+
+    if let Some(email) = &cfg.email {
+        notifiers.push(Box::new(EmailNotifier::new(email, &cfg.messages)));
+    }
+
+    notifiers
+}
@@ -0,0 +1,108 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use reqwest::Client;
+
+use checker_base::error::Error;
+
+use crate::config::Config;
+use crate::history::format_history;
+use crate::matrix::membership::leave_room;
+use crate::matrix::send::send_notice;
+use crate::storage::SharedStore;
+
+/// The prefix that identifies a message as a command for the bot.
+const COMMAND_PREFIX: &str = "!tbrust";
+
+/// The maximum number of entries to include in the response to the `history`
+/// command.
+const HISTORY_LIMIT: usize = 10;
+
+/// The text to send in response to the `help` command.
+const HELP_TEXT: &str = "Available commands:
+!tbrust help - show this message
+!tbrust history - show the recent checks and changes in sync status
+!tbrust leave - make the bot leave this room (allowed inviters only)";
+
+/// A command sent to the bot via a message in a room.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Command {
+    /// List the available commands.
+    Help,
+
+    /// Show the recent checks and changes in sync status.
+    History,
+
+    /// Leave the room the command was sent in.
+    Leave,
+
+    /// A command the bot doesn't know about.
+    Unknown(String),
+}
+
+impl Command {
+    /// Parse the given message body into a command.
+    ///
+    /// Returns `None` if the message isn't addressed to the bot.
+    pub fn parse(body: &str) -> Option<Command> {
+        let mut words = body.split_whitespace();
+
+        if words.next()? != COMMAND_PREFIX {
+            return None;
+        }
+
+        let command = match words.next() {
+            None | Some("help") => Command::Help,
+            Some("history") => Command::History,
+            Some("leave") => Command::Leave,
+            Some(other) => Command::Unknown(other.to_string()),
+        };
+
+        Some(command)
+    }
+}
+
+/// Execute a command sent by the given user in the given room.
+pub(super) async fn handle_command(
+    cfg: &Config,
+    client: Client,
+    store: &SharedStore,
+    room_id: &str,
+    sender: &str,
+    command: Command,
+) -> Result<(), Error> {
+    log::info!("Processing command {command:?} from {sender} in {room_id}");
+
+    match command {
+        Command::Help => {
+            send_notice(&cfg.matrix, client, room_id, HELP_TEXT).await?;
+        }
+        Command::History => {
+            let history = format_history(&store.lock().await.state().check_history, HISTORY_LIMIT);
+            send_notice(&cfg.matrix, client, room_id, &history).await?;
+        }
+        Command::Leave => {
+            // Only users allowed to invite the bot into rooms may tell it to
+            // leave them.
+            if !cfg
+                .matrix
+                .allowed_inviters
+                .iter()
+                .any(|user| user == sender)
+            {
+                log::warn!("Ignoring leave command from non-allowed user {sender}");
+                return Ok(());
+            }
+
+            leave_room(&cfg.matrix, client, room_id).await?;
+            log::info!("Left room {room_id}");
+        }
+        Command::Unknown(name) => {
+            let message = format!("Unknown command \"{name}\".\n\n{HELP_TEXT}");
+            send_notice(&cfg.matrix, client, room_id, &message).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1227 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, bail};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::sync::Mutex;
+use url::Url;
+use vodozemac::megolm::{
+    GroupSession, GroupSessionPickle, InboundGroupSession, InboundGroupSessionPickle,
+    MegolmMessage, SessionConfig as MegolmSessionConfig, SessionKey,
+};
+use vodozemac::olm::{
+    Account, AccountPickle, OlmMessage, Session, SessionConfig as OlmSessionConfig, SessionPickle,
+};
+use vodozemac::{Curve25519PublicKey, Ed25519PublicKey, Ed25519Signature};
+
+use checker_base::error::Error;
+
+use crate::config::{Config, MatrixConfig};
+use crate::storage::get_path_in_store;
+
+/// The name of the file in the store containing the bot's crypto state.
+const CRYPTO_STORE_FILE_NAME: &str = "crypto_store.json";
+
+/// The algorithm used to encrypt to-device messages.
+const OLM_ALGORITHM: &str = "m.olm.v1.curve25519-aes-sha2";
+
+/// The algorithm used to encrypt room messages.
+const MEGOLM_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+
+/// The number of messages after which an outbound group session is rotated.
+const MAX_MESSAGES_PER_GROUP_SESSION: u32 = 100;
+
+/// The age after which an outbound group session is rotated.
+const MAX_GROUP_SESSION_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The crypto state of the bot, if encryption is enabled in the configuration.
+///
+/// This is a global rather than something passed around with the rest of the
+/// Matrix configuration so that the code sending and receiving messages doesn't
+/// need to care whether encryption support is enabled.
+static MACHINE: OnceLock<Mutex<OlmMachine>> = OnceLock::new();
+
+/// The on-disk representation of the bot's crypto state.
+///
+/// All the Olm and Megolm objects are stored as encrypted pickles.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CryptoStore {
+    device_id: String,
+    account: String,
+    device_keys_uploaded: bool,
+    olm_sessions: HashMap<String, Vec<String>>,
+    inbound_group_sessions: HashMap<String, StoredInboundGroupSession>,
+    outbound_group_sessions: HashMap<String, StoredOutboundGroupSession>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredInboundGroupSession {
+    room_id: String,
+    /// Missing from stores written before the sender was tracked, in which
+    /// case messages from the session are rejected.
+    #[serde(default)]
+    sender: String,
+    sender_key: String,
+    pickle: String,
+    #[serde(default)]
+    message_indices: HashMap<u32, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredOutboundGroupSession {
+    pickle: String,
+    created_at_ms: u64,
+    shared_with: HashSet<String>,
+}
+
+/// A Megolm session used to decrypt messages in a room.
+struct InboundGroupSessionEntry {
+    room_id: String,
+    /// The user who shared the session with us.
+    sender: String,
+    /// The Curve25519 key of the device that shared the session with us.
+    sender_key: String,
+    session: InboundGroupSession,
+    /// The ID of the event each message index was decrypted from, to detect
+    /// replayed messages.
+    message_indices: HashMap<u32, String>,
+}
+
+/// A Megolm session used to encrypt messages the bot sends to a room.
+struct OutboundGroupSessionEntry {
+    session: GroupSession,
+    created_at_ms: u64,
+    /// The devices the session has been shared with, in the form
+    /// `user_id|device_id`.
+    shared_with: HashSet<String>,
+}
+
+/// A device belonging to a member of an encrypted room.
+struct Device {
+    user_id: String,
+    device_id: String,
+    curve25519: Curve25519PublicKey,
+    ed25519: Ed25519PublicKey,
+}
+
+impl Device {
+    fn key(&self) -> String {
+        format!("{}|{}", self.user_id, self.device_id)
+    }
+}
+
+/// The content of an `m.room.encrypted` event encrypted with Olm.
+#[derive(Debug, Deserialize)]
+struct OlmEventContent {
+    algorithm: String,
+    sender_key: String,
+    ciphertext: HashMap<String, OlmMessage>,
+}
+
+/// The decrypted payload of an Olm-encrypted to-device event.
+#[derive(Debug, Deserialize)]
+struct OlmPayload {
+    #[serde(rename = "type")]
+    event_type: String,
+    content: Value,
+    sender: String,
+    recipient: String,
+    recipient_keys: HashMap<String, String>,
+}
+
+/// The content of an `m.room_key` to-device event.
+#[derive(Debug, Deserialize)]
+struct RoomKeyContent {
+    algorithm: String,
+    room_id: String,
+    session_id: String,
+    session_key: String,
+}
+
+/// The content of an `m.room.encrypted` event encrypted with Megolm.
+#[derive(Debug, Deserialize)]
+struct MegolmEventContent {
+    algorithm: String,
+    ciphertext: String,
+    session_id: String,
+    /// Deprecated, but still sent by most clients.
+    #[serde(default)]
+    sender_key: Option<String>,
+}
+
+/// The decrypted payload of a Megolm-encrypted room event.
+#[derive(Debug, Deserialize)]
+struct MegolmPayload {
+    #[serde(rename = "type")]
+    event_type: String,
+    content: Value,
+    room_id: String,
+}
+
+/// The Olm account and sessions of the bot's device.
+struct OlmMachine {
+    user_id: String,
+    device_id: String,
+    pickle_key: [u8; 32],
+    path: PathBuf,
+    account: Account,
+    device_keys_uploaded: bool,
+    /// The Olm sessions with other devices, keyed by their Curve25519 key.
+    olm_sessions: HashMap<String, Vec<Session>>,
+    /// The Megolm sessions to decrypt room messages with, keyed by session ID.
+    inbound_group_sessions: HashMap<String, InboundGroupSessionEntry>,
+    /// The Megolm sessions to encrypt room messages with, keyed by room ID.
+    outbound_group_sessions: HashMap<String, OutboundGroupSessionEntry>,
+    /// Whether a given room is encrypted. This is only kept in memory, and a
+    /// room's entry is dropped when its encryption state changes.
+    encrypted_rooms: HashMap<String, bool>,
+}
+
+impl OlmMachine {
+    /// Load the crypto state from the given file, or create a new Olm account
+    /// if the file doesn't exist or belongs to a different device.
+    async fn load_or_create(
+        path: PathBuf,
+        pickle_key: [u8; 32],
+        user_id: &str,
+        device_id: &str,
+    ) -> io::Result<OlmMachine> {
+        let mut machine = OlmMachine {
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            pickle_key,
+            path,
+            account: Account::new(),
+            device_keys_uploaded: false,
+            olm_sessions: HashMap::new(),
+            inbound_group_sessions: HashMap::new(),
+            outbound_group_sessions: HashMap::new(),
+            encrypted_rooms: HashMap::new(),
+        };
+
+        let store: CryptoStore = match fs::read_to_string(&machine.path).await {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                log::info!("Creating new crypto store for device {device_id}");
+                return Ok(machine);
+            }
+            Err(err) => return Err(err),
+        };
+
+        if store.device_id != device_id {
+            log::warn!(
+                "Crypto store belongs to device {}, not {device_id}; starting afresh",
+                store.device_id
+            );
+            return Ok(machine);
+        }
+
+        // Corrupted pickles (or pickles encrypted with another key) are
+        // unlikely to fix themselves, so treat them the same as an invalid
+        // file.
+        let invalid_data = |err| io::Error::new(ErrorKind::InvalidData, err);
+
+        machine.account = Account::from_pickle(
+            AccountPickle::from_encrypted(&store.account, &pickle_key).map_err(invalid_data)?,
+        );
+        machine.device_keys_uploaded = store.device_keys_uploaded;
+
+        for (sender_key, pickles) in store.olm_sessions {
+            let sessions = pickles
+                .iter()
+                .map(|pickle| SessionPickle::from_encrypted(pickle, &pickle_key))
+                .map(|pickle| pickle.map(Session::from_pickle))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid_data)?;
+            machine.olm_sessions.insert(sender_key, sessions);
+        }
+
+        for (session_id, stored) in store.inbound_group_sessions {
+            let pickle = InboundGroupSessionPickle::from_encrypted(&stored.pickle, &pickle_key)
+                .map_err(invalid_data)?;
+            machine.inbound_group_sessions.insert(
+                session_id,
+                InboundGroupSessionEntry {
+                    room_id: stored.room_id,
+                    sender: stored.sender,
+                    sender_key: stored.sender_key,
+                    session: InboundGroupSession::from_pickle(pickle),
+                    message_indices: stored.message_indices,
+                },
+            );
+        }
+
+        for (room_id, stored) in store.outbound_group_sessions {
+            let pickle = GroupSessionPickle::from_encrypted(&stored.pickle, &pickle_key)
+                .map_err(invalid_data)?;
+            machine.outbound_group_sessions.insert(
+                room_id,
+                OutboundGroupSessionEntry {
+                    session: GroupSession::from_pickle(pickle),
+                    created_at_ms: stored.created_at_ms,
+                    shared_with: stored.shared_with,
+                },
+            );
+        }
+
+        Ok(machine)
+    }
+
+    /// Persist the crypto state to disk.
+    ///
+    /// The state is first written to a temporary file which is then renamed,
+    /// so a crash mid-write doesn't leave a truncated file behind.
+    async fn save(&self) -> io::Result<()> {
+        let key = &self.pickle_key;
+
+        let store = CryptoStore {
+            device_id: self.device_id.clone(),
+            account: self.account.pickle().encrypt(key),
+            device_keys_uploaded: self.device_keys_uploaded,
+            olm_sessions: self
+                .olm_sessions
+                .iter()
+                .map(|(sender_key, sessions)| {
+                    let pickles = sessions.iter().map(|s| s.pickle().encrypt(key)).collect();
+                    (sender_key.clone(), pickles)
+                })
+                .collect(),
+            inbound_group_sessions: self
+                .inbound_group_sessions
+                .iter()
+                .map(|(session_id, entry)| {
+                    let stored = StoredInboundGroupSession {
+                        room_id: entry.room_id.clone(),
+                        sender: entry.sender.clone(),
+                        sender_key: entry.sender_key.clone(),
+                        pickle: entry.session.pickle().encrypt(key),
+                        message_indices: entry.message_indices.clone(),
+                    };
+                    (session_id.clone(), stored)
+                })
+                .collect(),
+            outbound_group_sessions: self
+                .outbound_group_sessions
+                .iter()
+                .map(|(room_id, entry)| {
+                    let stored = StoredOutboundGroupSession {
+                        pickle: entry.session.pickle().encrypt(key),
+                        created_at_ms: entry.created_at_ms,
+                        shared_with: entry.shared_with.clone(),
+                    };
+                    (room_id.clone(), stored)
+                })
+                .collect(),
+        };
+
+        let mut tmp_path = self.path.clone();
+        tmp_path.set_extension("json.tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec(&store)?).await?;
+        fs::rename(&tmp_path, &self.path).await?;
+
+        Ok(())
+    }
+
+    /// Sign the given JSON object with the device's Ed25519 key, and add the
+    /// signature to it.
+    ///
+    /// This relies on `serde_json` sorting object keys and not adding any
+    /// whitespace, which matches the Matrix canonical JSON format for the
+    /// objects we sign.
+    fn sign_json(&self, value: &mut Value) -> Result<(), Error> {
+        let canonical = serde_json::to_string(value)?;
+        let signature = self.account.sign(canonical.as_bytes());
+
+        value["signatures"] = json!({
+            &self.user_id: {
+                format!("ed25519:{}", self.device_id): signature.to_base64(),
+            },
+        });
+
+        Ok(())
+    }
+
+    /// Build the signed device keys of the bot's device.
+    fn device_keys(&self) -> Result<Value, Error> {
+        let mut device_keys = json!({
+            "user_id": self.user_id,
+            "device_id": self.device_id,
+            "algorithms": [OLM_ALGORITHM, MEGOLM_ALGORITHM],
+            "keys": {
+                format!("curve25519:{}", self.device_id): self.account.curve25519_key().to_base64(),
+                format!("ed25519:{}", self.device_id): self.account.ed25519_key().to_base64(),
+            },
+        });
+
+        self.sign_json(&mut device_keys)?;
+
+        Ok(device_keys)
+    }
+
+    /// Upload the device keys if they haven't been uploaded yet, as well as
+    /// new one-time keys if the server is running low on them.
+    async fn upload_keys(
+        &mut self,
+        matrix_cfg: &MatrixConfig,
+        client: Client,
+        one_time_key_count: Option<usize>,
+    ) -> Result<(), Error> {
+        let mut body = Map::new();
+
+        if !self.device_keys_uploaded {
+            body.insert("device_keys".into(), self.device_keys()?);
+        }
+
+        let max_keys = self.account.max_number_of_one_time_keys();
+        if let Some(count) = one_time_key_count
+            && count < max_keys / 2
+        {
+            self.account.generate_one_time_keys(max_keys - count);
+        }
+
+        let mut one_time_keys = Map::new();
+        for (key_id, key) in self.account.one_time_keys() {
+            let mut signed_key = json!({ "key": key.to_base64() });
+            self.sign_json(&mut signed_key)?;
+            one_time_keys.insert(
+                format!("signed_curve25519:{}", key_id.to_base64()),
+                signed_key,
+            );
+        }
+
+        if !one_time_keys.is_empty() {
+            body.insert("one_time_keys".into(), Value::Object(one_time_keys));
+        }
+
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        // Persist the newly generated keys before uploading them, so we don't
+        // end up with the server handing out keys we don't know about.
+        self.save().await?;
+
+        client
+            .post(api_url(matrix_cfg, "keys/upload")?)
+            .bearer_auth(&matrix_cfg.access_token)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        log::debug!("Uploaded keys: {:?}", body.keys().collect::<Vec<_>>());
+
+        self.account.mark_keys_as_published();
+        self.device_keys_uploaded = true;
+        self.save().await?;
+
+        Ok(())
+    }
+
+    /// Decrypt an Olm-encrypted to-device event, and store the room key it
+    /// contains (if any).
+    fn handle_to_device_event(&mut self, sender: &str, content: &Value) -> anyhow::Result<()> {
+        let content: OlmEventContent = serde_json::from_value(content.clone())?;
+        if content.algorithm != OLM_ALGORITHM {
+            bail!("unsupported algorithm {}", content.algorithm);
+        }
+
+        let our_key = self.account.curve25519_key().to_base64();
+        let message = content
+            .ciphertext
+            .get(&our_key)
+            .context("event not encrypted for this device")?;
+
+        let plaintext = self.decrypt_olm(&content.sender_key, message)?;
+        let payload: OlmPayload = serde_json::from_slice(&plaintext)?;
+
+        // Make sure the event was actually meant for us, and that the sender
+        // wasn't impersonated.
+        if payload.sender != sender || payload.recipient != self.user_id {
+            bail!("sender or recipient mismatch");
+        }
+        let our_ed25519 = self.account.ed25519_key().to_base64();
+        if payload.recipient_keys.get("ed25519") != Some(&our_ed25519) {
+            bail!("recipient key mismatch");
+        }
+
+        if payload.event_type != "m.room_key" {
+            log::debug!("Ignoring encrypted to-device event {}", payload.event_type);
+            return Ok(());
+        }
+
+        let room_key: RoomKeyContent = serde_json::from_value(payload.content)?;
+        if room_key.algorithm != MEGOLM_ALGORITHM {
+            bail!("unsupported room key algorithm {}", room_key.algorithm);
+        }
+
+        let session_key = SessionKey::from_base64(&room_key.session_key)?;
+        let session = InboundGroupSession::new(&session_key, MegolmSessionConfig::version_1());
+        if session.session_id() != room_key.session_id {
+            bail!("session ID mismatch");
+        }
+
+        log::info!(
+            "Received room key for {} from {sender} (session {})",
+            room_key.room_id,
+            room_key.session_id
+        );
+
+        self.inbound_group_sessions.insert(
+            room_key.session_id,
+            InboundGroupSessionEntry {
+                room_id: room_key.room_id,
+                sender: sender.to_string(),
+                sender_key: content.sender_key,
+                session,
+                message_indices: HashMap::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Decrypt an Olm message sent by the device with the given Curve25519
+    /// key, creating a new Olm session if needed.
+    fn decrypt_olm(&mut self, sender_key: &str, message: &OlmMessage) -> anyhow::Result<Vec<u8>> {
+        if let Some(sessions) = self.olm_sessions.get_mut(sender_key) {
+            for session in sessions.iter_mut() {
+                if let Ok(plaintext) = session.decrypt(message) {
+                    return Ok(plaintext);
+                }
+            }
+        }
+
+        let OlmMessage::PreKey(message) = message else {
+            bail!("no Olm session can decrypt the message");
+        };
+
+        let their_key = Curve25519PublicKey::from_base64(sender_key)?;
+        let result = self.account.create_inbound_session(their_key, message)?;
+
+        self.olm_sessions
+            .entry(sender_key.to_string())
+            .or_default()
+            .push(result.session);
+
+        Ok(result.plaintext)
+    }
+
+    /// Decrypt a Megolm-encrypted room event with the given ID, sent by the
+    /// given user.
+    ///
+    /// The event is rejected if the session it was encrypted with wasn't shared
+    /// with us by its sender, or if another event was already decrypted at the
+    /// same message index (i.e. the message was replayed).
+    ///
+    /// The unencrypted `m.relates_to` property of the event, if any, is added
+    /// to the decrypted content.
+    fn decrypt_room_event(
+        &mut self,
+        room_id: &str,
+        event_id: &str,
+        sender: &str,
+        encrypted: &Value,
+    ) -> anyhow::Result<(String, Value)> {
+        let content: MegolmEventContent = serde_json::from_value(encrypted.clone())?;
+        if content.algorithm != MEGOLM_ALGORITHM {
+            bail!("unsupported algorithm {}", content.algorithm);
+        }
+
+        let entry = self
+            .inbound_group_sessions
+            .get_mut(&content.session_id)
+            .with_context(|| format!("unknown session {}", content.session_id))?;
+
+        if entry.room_id != room_id {
+            bail!("session {} belongs to another room", content.session_id);
+        }
+        if entry.sender != sender {
+            bail!("session {} wasn't shared by {sender}", content.session_id);
+        }
+        if content
+            .sender_key
+            .is_some_and(|sender_key| sender_key != entry.sender_key)
+        {
+            bail!("sender key mismatch for session {}", content.session_id);
+        }
+
+        let message = MegolmMessage::from_base64(&content.ciphertext)?;
+        let decrypted = entry.session.decrypt(&message)?;
+        let payload: MegolmPayload = serde_json::from_slice(&decrypted.plaintext)?;
+
+        if payload.room_id != room_id {
+            bail!("room ID mismatch");
+        }
+
+        match entry.message_indices.get(&decrypted.message_index) {
+            Some(known_event_id) if known_event_id != event_id => bail!(
+                "message index {} of session {} replayed (first seen in {known_event_id})",
+                decrypted.message_index,
+                content.session_id
+            ),
+            Some(_) => {}
+            None => {
+                entry
+                    .message_indices
+                    .insert(decrypted.message_index, event_id.to_string());
+            }
+        }
+
+        let mut decrypted_content = payload.content;
+        if let Some(relates_to) = encrypted.get("m.relates_to")
+            && let Some(decrypted_content) = decrypted_content.as_object_mut()
+        {
+            decrypted_content.insert("m.relates_to".to_string(), relates_to.clone());
+        }
+
+        Ok((payload.event_type, decrypted_content))
+    }
+
+    /// Check whether the given room is encrypted.
+    async fn is_room_encrypted(
+        &mut self,
+        matrix_cfg: &MatrixConfig,
+        client: Client,
+        room_id: &str,
+    ) -> Result<bool, Error> {
+        if let Some(encrypted) = self.encrypted_rooms.get(room_id) {
+            return Ok(*encrypted);
+        }
+
+        let path = format!("rooms/{room_id}/state/m.room.encryption");
+        let response = client
+            .get(api_url(matrix_cfg, &path)?)
+            .bearer_auth(&matrix_cfg.access_token)
+            .send()
+            .await?;
+
+        // The server responds with a 404 if the room has no encryption state
+        // event.
+        let encrypted = match response.status() {
+            StatusCode::NOT_FOUND => false,
+            _ => {
+                response.error_for_status()?;
+                true
+            }
+        };
+
+        self.encrypted_rooms.insert(room_id.to_string(), encrypted);
+
+        Ok(encrypted)
+    }
+
+    /// Forget the cached encryption state and the outbound group session of
+    /// the given room after a change to its state, if needed.
+    ///
+    /// The encryption state is looked up again the next time the bot sends a
+    /// message to the room. The outbound group session is discarded when a
+    /// member leaves the room (or is kicked or banned from it), so that they
+    /// can't decrypt the messages sent after that.
+    ///
+    /// Returns whether the state that is persisted changed.
+    fn process_state_event(&mut self, room_id: &str, event_type: &str, content: &Value) -> bool {
+        match event_type {
+            "m.room.encryption" => {
+                self.encrypted_rooms.remove(room_id);
+                false
+            }
+            "m.room.member" => {
+                let left = matches!(
+                    content.get("membership").and_then(Value::as_str),
+                    Some("leave" | "ban")
+                );
+                if !left || self.outbound_group_sessions.remove(room_id).is_none() {
+                    return false;
+                }
+
+                log::info!("Discarding outbound group session for {room_id} after a member left");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Encrypt the given room event content if the room is encrypted, sharing
+    /// the room key with the devices of the room's members if needed.
+    ///
+    /// The `m.relates_to` property of the content (e.g. for replies) is left
+    /// unencrypted, as required by the specification.
+    ///
+    /// Returns `None` if the room isn't encrypted.
+    async fn encrypt_room_event(
+        &mut self,
+        matrix_cfg: &MatrixConfig,
+        client: Client,
+        room_id: &str,
+        event_type: &str,
+        mut content: Value,
+    ) -> Result<Option<Value>, Error> {
+        if !self
+            .is_room_encrypted(matrix_cfg, client.clone(), room_id)
+            .await?
+        {
+            return Ok(None);
+        }
+
+        let members = joined_members(matrix_cfg, client.clone(), room_id).await?;
+        let devices = query_devices(matrix_cfg, client.clone(), &members).await?;
+        let device_keys = devices.iter().map(Device::key).collect::<HashSet<_>>();
+
+        let now_ms = now_ms();
+
+        // Rotate the session if it has been used too much or for too long, or
+        // if it was shared with a device that is no longer in the room (e.g.
+        // because its owner left, or it was deleted).
+        let needs_rotation = match self.outbound_group_sessions.get(room_id) {
+            Some(entry) => {
+                entry.session.message_index() >= MAX_MESSAGES_PER_GROUP_SESSION
+                    || now_ms.saturating_sub(entry.created_at_ms)
+                        >= MAX_GROUP_SESSION_AGE.as_millis() as u64
+                    || !entry.shared_with.is_subset(&device_keys)
+            }
+            None => true,
+        };
+
+        if needs_rotation {
+            log::info!("Creating new outbound group session for {room_id}");
+            self.outbound_group_sessions.insert(
+                room_id.to_string(),
+                OutboundGroupSessionEntry {
+                    session: GroupSession::new(MegolmSessionConfig::version_1()),
+                    created_at_ms: now_ms,
+                    shared_with: HashSet::new(),
+                },
+            );
+        }
+
+        self.share_group_session(matrix_cfg, client, room_id, devices)
+            .await?;
+
+        // The entry has been created above if it didn't exist, so unwrapping
+        // should not panic.
+        let entry = self.outbound_group_sessions.get_mut(room_id).unwrap();
+
+        let relates_to = content
+            .as_object_mut()
+            .and_then(|content| content.remove("m.relates_to"));
+
+        let payload = json!({
+            "type": event_type,
+            "content": content,
+            "room_id": room_id,
+        });
+        let message = entry.session.encrypt(serde_json::to_string(&payload)?);
+        let session_id = entry.session.session_id();
+
+        self.save().await?;
+
+        let mut encrypted = json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "sender_key": self.account.curve25519_key().to_base64(),
+            "ciphertext": message.to_base64(),
+            "session_id": session_id,
+            "device_id": self.device_id,
+        });
+        if let Some(relates_to) = relates_to {
+            encrypted["m.relates_to"] = relates_to;
+        }
+
+        Ok(Some(encrypted))
+    }
+
+    /// Share the room's outbound group session with the given devices (i.e.
+    /// those of the room's members) it hasn't been shared with yet.
+    async fn share_group_session(
+        &mut self,
+        matrix_cfg: &MatrixConfig,
+        client: Client,
+        room_id: &str,
+        devices: Vec<Device>,
+    ) -> Result<(), Error> {
+        let entry = &self.outbound_group_sessions[room_id];
+        let devices = devices
+            .into_iter()
+            .filter(|d| !(d.user_id == self.user_id && d.device_id == self.device_id))
+            .filter(|d| !entry.shared_with.contains(&d.key()))
+            .collect::<Vec<_>>();
+
+        if devices.is_empty() {
+            return Ok(());
+        }
+
+        // Establish Olm sessions with the devices we don't have one with yet.
+        let missing = devices
+            .iter()
+            .filter(|d| !self.olm_sessions.contains_key(&d.curve25519.to_base64()))
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            let one_time_keys = claim_one_time_keys(matrix_cfg, client.clone(), &missing).await?;
+            for device in missing {
+                let Some(one_time_key) = one_time_keys.get(&device.key()) else {
+                    log::warn!("No one-time key available for device {}", device.key());
+                    continue;
+                };
+
+                let session = self.account.create_outbound_session(
+                    OlmSessionConfig::version_1(),
+                    device.curve25519,
+                    *one_time_key,
+                );
+
+                self.olm_sessions
+                    .entry(device.curve25519.to_base64())
+                    .or_default()
+                    .push(session);
+            }
+        }
+
+        let (session_id, session_key) = {
+            let entry = &self.outbound_group_sessions[room_id];
+            (entry.session.session_id(), entry.session.session_key())
+        };
+
+        let room_key = json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "room_id": room_id,
+            "session_id": session_id,
+            "session_key": session_key.to_base64(),
+        });
+
+        let our_curve25519 = self.account.curve25519_key().to_base64();
+        let our_ed25519 = self.account.ed25519_key().to_base64();
+
+        let mut messages: HashMap<String, Map<String, Value>> = HashMap::new();
+        let mut shared_with = Vec::new();
+
+        for device in &devices {
+            let their_curve25519 = device.curve25519.to_base64();
+            let Some(session) = self
+                .olm_sessions
+                .get_mut(&their_curve25519)
+                .and_then(|sessions| sessions.last_mut())
+            else {
+                continue;
+            };
+
+            let payload = json!({
+                "type": "m.room_key",
+                "content": room_key,
+                "sender": self.user_id,
+                "sender_device": self.device_id,
+                "keys": { "ed25519": our_ed25519 },
+                "recipient": device.user_id,
+                "recipient_keys": { "ed25519": device.ed25519.to_base64() },
+            });
+
+            let message = session.encrypt(serde_json::to_string(&payload)?);
+            let content = json!({
+                "algorithm": OLM_ALGORITHM,
+                "sender_key": our_curve25519,
+                "ciphertext": { their_curve25519: message },
+            });
+
+            messages
+                .entry(device.user_id.clone())
+                .or_default()
+                .insert(device.device_id.clone(), content);
+            shared_with.push(device.key());
+        }
+
+        // Save the Olm sessions' new state before sending the keys, so a crash
+        // doesn't make us reuse a message key.
+        self.save().await?;
+
+        let path = format!("sendToDevice/m.room.encrypted/{}", now_ms());
+        client
+            .put(api_url(matrix_cfg, &path)?)
+            .bearer_auth(&matrix_cfg.access_token)
+            .json(&json!({ "messages": messages }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        log::info!(
+            "Shared room key for {room_id} with {} device(s)",
+            shared_with.len()
+        );
+
+        // The entry was looked up above, so unwrapping should not panic.
+        let entry = self.outbound_group_sessions.get_mut(room_id).unwrap();
+        entry.shared_with.extend(shared_with);
+        self.save().await?;
+
+        Ok(())
+    }
+}
+
+/// Build the URL to the given client-server API endpoint.
+fn api_url(matrix_cfg: &MatrixConfig, path: &str) -> Result<Url, Error> {
+    let url = format!(
+        "https://{}/_matrix/client/v3/{}",
+        matrix_cfg.server_host, path
+    );
+    Ok(Url::parse(&url)?)
+}
+
+/// The current time as milliseconds since the UNIX epoch.
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        // We know the current time is after the UNIX epoch, so unwrapping
+        // should not panic.
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Check that the given JSON object has been signed by the given key.
+fn verify_signed_json(value: &Value, user_id: &str, key_id: &str, key: &Ed25519PublicKey) -> bool {
+    let Some(signature) = value
+        .pointer(&format!("/signatures/{user_id}/{key_id}"))
+        .and_then(Value::as_str)
+        .and_then(|signature| Ed25519Signature::from_base64(signature).ok())
+    else {
+        return false;
+    };
+
+    let mut unsigned = value.clone();
+    if let Some(object) = unsigned.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+
+    let Ok(canonical) = serde_json::to_string(&unsigned) else {
+        return false;
+    };
+
+    key.verify(canonical.as_bytes(), &signature).is_ok()
+}
+
+/// Retrieve the IDs of the users currently in the given room.
+async fn joined_members(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    room_id: &str,
+) -> Result<Vec<String>, Error> {
+    #[derive(Deserialize)]
+    struct JoinedMembersResponse {
+        joined: HashMap<String, Value>,
+    }
+
+    let path = format!("rooms/{room_id}/joined_members");
+    let response: JoinedMembersResponse = client
+        .get(api_url(matrix_cfg, &path)?)
+        .bearer_auth(&matrix_cfg.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(response.joined.into_keys().collect())
+}
+
+/// Retrieve the devices of the given users, discarding any device whose keys
+/// aren't correctly signed.
+async fn query_devices(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    users: &[String],
+) -> Result<Vec<Device>, Error> {
+    #[derive(Deserialize)]
+    struct KeysQueryResponse {
+        device_keys: HashMap<String, HashMap<String, Value>>,
+    }
+
+    let device_keys = users
+        .iter()
+        .map(|user| (user.clone(), json!([])))
+        .collect::<Map<_, _>>();
+
+    let response: KeysQueryResponse = client
+        .post(api_url(matrix_cfg, "keys/query")?)
+        .bearer_auth(&matrix_cfg.access_token)
+        .json(&json!({ "device_keys": device_keys }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut devices = Vec::new();
+    for (user_id, user_devices) in response.device_keys {
+        for (device_id, keys) in user_devices {
+            let key = |algorithm: &str| {
+                keys.pointer(&format!("/keys/{algorithm}:{device_id}"))
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            };
+
+            let (Some(curve25519), Some(ed25519)) = (key("curve25519"), key("ed25519")) else {
+                continue;
+            };
+
+            let (Ok(curve25519), Ok(ed25519)) = (
+                Curve25519PublicKey::from_base64(&curve25519),
+                Ed25519PublicKey::from_base64(&ed25519),
+            ) else {
+                continue;
+            };
+
+            let ids_match =
+                keys["user_id"] == user_id.as_str() && keys["device_id"] == device_id.as_str();
+            let key_id = format!("ed25519:{device_id}");
+            if !ids_match || !verify_signed_json(&keys, &user_id, &key_id, &ed25519) {
+                log::warn!("Ignoring device {user_id}|{device_id} with invalid keys");
+                continue;
+            }
+
+            devices.push(Device {
+                user_id: user_id.clone(),
+                device_id,
+                curve25519,
+                ed25519,
+            });
+        }
+    }
+
+    Ok(devices)
+}
+
+/// Claim a one-time key for each of the given devices.
+///
+/// Returns the claimed keys, keyed by `user_id|device_id`. Devices for which no
+/// correctly signed key could be claimed are omitted.
+async fn claim_one_time_keys(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    devices: &[&Device],
+) -> Result<HashMap<String, Curve25519PublicKey>, Error> {
+    #[derive(Deserialize)]
+    struct KeysClaimResponse {
+        one_time_keys: HashMap<String, HashMap<String, HashMap<String, Value>>>,
+    }
+
+    let mut request: HashMap<&str, HashMap<&str, &str>> = HashMap::new();
+    for device in devices {
+        request
+            .entry(&device.user_id)
+            .or_default()
+            .insert(&device.device_id, "signed_curve25519");
+    }
+
+    let response: KeysClaimResponse = client
+        .post(api_url(matrix_cfg, "keys/claim")?)
+        .bearer_auth(&matrix_cfg.access_token)
+        .json(&json!({ "one_time_keys": request }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut keys = HashMap::new();
+    for device in devices {
+        let Some(claimed) = response
+            .one_time_keys
+            .get(&device.user_id)
+            .and_then(|user_keys| user_keys.get(&device.device_id))
+            .and_then(|device_keys| device_keys.values().next())
+        else {
+            continue;
+        };
+
+        let key_id = format!("ed25519:{}", device.device_id);
+        if !verify_signed_json(claimed, &device.user_id, &key_id, &device.ed25519) {
+            log::warn!("Ignoring badly signed one-time key for {}", device.key());
+            continue;
+        }
+
+        if let Some(Ok(key)) = claimed["key"]
+            .as_str()
+            .map(Curve25519PublicKey::from_base64)
+        {
+            keys.insert(device.key(), key);
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Set up encryption support if it is enabled in the configuration.
+///
+/// This loads the crypto state from the store (or creates a new Olm account),
+/// and uploads the device's keys if needed.
+pub(super) async fn init(
+    cfg: &Config,
+    client: Client,
+    user_id: &str,
+    device_id: Option<&str>,
+) -> Result<(), Error> {
+    let Some(encryption_cfg) = &cfg.matrix.encryption else {
+        return Ok(());
+    };
+
+    let Some(device_id) = device_id else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "encryption requires an access token associated with a device",
+        )
+        .into());
+    };
+
+    let pickle_key: [u8; 32] = Sha256::digest(encryption_cfg.pickle_key.as_bytes()).into();
+    let path = get_path_in_store(cfg, CRYPTO_STORE_FILE_NAME)?;
+
+    let mut machine = OlmMachine::load_or_create(path, pickle_key, user_id, device_id).await?;
+
+    // A new account doesn't have any one-time key on the server yet.
+    let one_time_key_count = match machine.device_keys_uploaded {
+        true => None,
+        false => Some(0),
+    };
+    machine
+        .upload_keys(&cfg.matrix, client, one_time_key_count)
+        .await?;
+
+    log::info!(
+        "Encryption enabled for device {device_id} (identity key {})",
+        machine.account.curve25519_key().to_base64()
+    );
+
+    // `init` is only called once at startup, so the cell is always empty here.
+    let _ = MACHINE.set(Mutex::new(machine));
+
+    Ok(())
+}
+
+/// Process the crypto-related parts of a sync response: one-time key counts
+/// and to-device events.
+///
+/// Failing to decrypt a to-device event isn't fatal: it is logged and the event
+/// is ignored.
+pub(super) async fn process_sync(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    one_time_key_counts: &HashMap<String, usize>,
+    to_device_events: impl Iterator<Item = (&str, &str, &Value)>,
+) -> Result<(), Error> {
+    let Some(machine) = MACHINE.get() else {
+        return Ok(());
+    };
+    let mut machine = machine.lock().await;
+
+    let mut changed = false;
+    for (event_type, sender, content) in to_device_events {
+        if event_type != "m.room.encrypted" {
+            continue;
+        }
+
+        changed = true;
+        if let Err(err) = machine.handle_to_device_event(sender, content) {
+            log::warn!("Failed to process encrypted to-device event from {sender}: {err}");
+        }
+    }
+
+    if changed {
+        machine.save().await?;
+    }
+
+    if let Some(count) = one_time_key_counts.get("signed_curve25519") {
+        machine
+            .upload_keys(matrix_cfg, client, Some(*count))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Process the state events (and state changes in the timeline) of a room the
+/// bot is in, see [`OlmMachine::process_state_event`].
+pub(super) async fn process_room_state<'a>(
+    room_id: &str,
+    events: impl Iterator<Item = (&'a str, &'a Value)>,
+) -> Result<(), Error> {
+    let Some(machine) = MACHINE.get() else {
+        return Ok(());
+    };
+    let mut machine = machine.lock().await;
+
+    let mut changed = false;
+    for (event_type, content) in events {
+        changed |= machine.process_state_event(room_id, event_type, content);
+    }
+
+    if changed {
+        machine.save().await?;
+    }
+
+    Ok(())
+}
+
+/// Decrypt the content of an `m.room.encrypted` event in the given room.
+///
+/// Returns the type and content of the decrypted event, or `None` if it can't
+/// be decrypted (in which case the reason is logged).
+pub(super) async fn decrypt_room_event(
+    room_id: &str,
+    event_id: &str,
+    sender: &str,
+    content: &Value,
+) -> Result<Option<(String, Value)>, Error> {
+    let Some(machine) = MACHINE.get() else {
+        return Ok(None);
+    };
+    let mut machine = machine.lock().await;
+
+    match machine.decrypt_room_event(room_id, event_id, sender, content) {
+        Ok(decrypted) => {
+            // Persist the message index, so a replay is noticed even after a
+            // restart.
+            machine.save().await?;
+            Ok(Some(decrypted))
+        }
+        Err(err) => {
+            log::warn!("Failed to decrypt event {event_id} in {room_id}: {err}");
+            Ok(None)
+        }
+    }
+}
+
+/// Encrypt the given event if the room it's being sent to is encrypted.
+///
+/// Returns the type and content of the event to actually send to the room.
+pub(super) async fn encrypt_room_event(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    room_id: &str,
+    event_type: &str,
+    content: Value,
+) -> Result<(String, Value), Error> {
+    let Some(machine) = MACHINE.get() else {
+        return Ok((event_type.to_string(), content));
+    };
+    let mut machine = machine.lock().await;
+
+    let encrypted = machine
+        .encrypt_room_event(matrix_cfg, client, room_id, event_type, content.clone())
+        .await?;
+
+    match encrypted {
+        Some(encrypted) => Ok(("m.room.encrypted".to_string(), encrypted)),
+        None => Ok((event_type.to_string(), content)),
+    }
+}
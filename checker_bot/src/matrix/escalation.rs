@@ -0,0 +1,121 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use reqwest::Client;
+
+use checker_base::error::Error;
+
+use crate::config::Config;
+use crate::matrix::send::send_formatted_notice;
+use crate::storage::{CheckOutcome, SharedStore, now_ts};
+use crate::template::CheckDetails;
+
+/// The number of seconds in an hour.
+const HOUR: u64 = 3600;
+
+/// Escalate the ongoing incident, if there is one and it has lasted long enough
+/// according to the escalation policy in the configuration.
+///
+/// There are two stages of escalation, each happening at most once per
+/// incident: first a reminder is sent to the Matrix room (as a reply to the
+/// message that reported the dependencies out of date), then a message is sent
+/// to the escalation room. Both can mention Matrix users.
+pub(super) async fn process_escalation(
+    cfg: &Config,
+    client: Client,
+    store: &SharedStore,
+) -> Result<(), Error> {
+    let Some(escalation) = &cfg.escalation else {
+        return Ok(());
+    };
+
+    let (incident, last_check) = {
+        let store = store.lock().await;
+        let state = store.state();
+        let last_check = state.check_history.iter().rfind(|record| {
+            record.outcome == CheckOutcome::OutOfDate && record.trigger.tracks_sync_status()
+        });
+        (state.incident.clone(), last_check.cloned())
+    };
+
+    let Some(incident) = incident else {
+        return Ok(());
+    };
+
+    let out_of_sync_for = now_ts().saturating_sub(incident.since);
+
+    // Describe the incident using the last check that found the dependencies
+    // out of sync.
+    let mut details = CheckDetails {
+        out_of_sync_for: Some(Duration::from_secs(out_of_sync_for)),
+        ..Default::default()
+    };
+    if let Some(record) = last_check {
+        details.moz_rev = record.moz_rev;
+        details.comm_rev = record.tb_rev;
+        details.mismatched_files = record
+            .files
+            .iter()
+            .filter(|file| !file.matches())
+            .map(|file| file.path.clone())
+            .collect();
+        details.duration = Duration::from_millis(record.duration_ms);
+    }
+
+    if let Some(reminder) = &escalation.reminder
+        && !incident.reminder_sent
+        && out_of_sync_for >= reminder.after_hours * HOUR
+    {
+        log::info!("Sending reminder about out of date dependencies");
+
+        let message = reminder
+            .message
+            .render(&details)
+            .with_mentions(&reminder.mentions);
+
+        send_formatted_notice(
+            &cfg.matrix,
+            client.clone(),
+            &cfg.matrix.room_id,
+            &message,
+            incident.notice_event_id.as_deref(),
+        )
+        .await?;
+
+        store
+            .lock()
+            .await
+            .update(|state| {
+                if let Some(incident) = &mut state.incident {
+                    incident.reminder_sent = true;
+                }
+            })
+            .await?;
+    }
+
+    if let Some(room) = &escalation.room
+        && !incident.escalated
+        && out_of_sync_for >= room.after_hours * HOUR
+    {
+        log::info!("Escalating out of date dependencies to {}", room.room_id);
+
+        let message = room.message.render(&details).with_mentions(&room.mentions);
+
+        send_formatted_notice(&cfg.matrix, client, &room.room_id, &message, None).await?;
+
+        store
+            .lock()
+            .await
+            .update(|state| {
+                if let Some(incident) = &mut state.incident {
+                    incident.escalated = true;
+                }
+            })
+            .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+
+use checker_base::error::Error;
+
+use crate::config::MatrixConfig;
+
+/// A response to a `/joined_rooms` request.
+///
+/// See <https://spec.matrix.org/v1.14/client-server-api/#get_matrixclientv3joined_rooms>
+#[derive(Debug, Clone, Deserialize)]
+struct JoinedRoomsResponse {
+    joined_rooms: Vec<String>,
+}
+
+/// Join the room with the given ID, which the bot must have been invited to.
+pub(super) async fn join_room(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    room_id: &str,
+) -> Result<(), Error> {
+    let url = format!(
+        "https://{}/_matrix/client/v3/rooms/{}/join",
+        matrix_cfg.server_host, room_id
+    );
+    let url = Url::parse(&url)?;
+
+    client
+        .post(url)
+        .bearer_auth(&matrix_cfg.access_token)
+        .body("{}")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Leave the room with the given ID.
+///
+/// If the bot has only been invited to the room, this rejects the invite.
+pub(super) async fn leave_room(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    room_id: &str,
+) -> Result<(), Error> {
+    let url = format!(
+        "https://{}/_matrix/client/v3/rooms/{}/leave",
+        matrix_cfg.server_host, room_id
+    );
+    let url = Url::parse(&url)?;
+
+    client
+        .post(url)
+        .bearer_auth(&matrix_cfg.access_token)
+        .body("{}")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Retrieve the IDs of all the rooms the bot is currently in.
+pub(super) async fn joined_rooms(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+) -> Result<Vec<String>, Error> {
+    let url = format!(
+        "https://{}/_matrix/client/v3/joined_rooms",
+        matrix_cfg.server_host
+    );
+    let url = Url::parse(&url)?;
+
+    let response: JoinedRoomsResponse = client
+        .get(url)
+        .bearer_auth(&matrix_cfg.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(response.joined_rooms)
+}
@@ -0,0 +1,74 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use reqwest::Client;
+
+use crate::config::Config;
+use crate::matrix::send::send_formatted_notice;
+use crate::notifier::{CheckReport, Notifier, NotifyFuture};
+use crate::storage::{CheckOutcome, Trigger};
+
+/// A notifier sending a notice to the Matrix room for notable checks, using
+/// the message templates from the configuration.
+///
+/// If the dependencies are back in sync, the notice is sent as a reply to the
+/// message that reported them out of date. Checks requested over HTTP are
+/// reported with their own message, whatever their outcome.
+pub(super) struct MatrixNotifier<'a> {
+    cfg: &'a Config,
+    client: Client,
+}
+
+impl<'a> MatrixNotifier<'a> {
+    pub fn new(cfg: &'a Config, client: Client) -> MatrixNotifier<'a> {
+        MatrixNotifier { cfg, client }
+    }
+}
+
+impl Notifier for MatrixNotifier<'_> {
+    fn name(&self) -> String {
+        format!("Matrix room {}", self.cfg.matrix.room_id)
+    }
+
+    fn notify<'a>(&'a self, report: &'a CheckReport<'a>) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            if !report.notable {
+                return Ok(None);
+            }
+
+            let messages = &self.cfg.messages;
+            let template = match (report.trigger, report.outcome) {
+                (Trigger::HttpRequest, _) => &messages.manual_check,
+                (_, CheckOutcome::UpToDate) => {
+                    match (&messages.deps_back_in_sync, report.back_in_sync) {
+                        (Some(template), true) => template,
+                        _ => &messages.deps_up_to_date,
+                    }
+                }
+                (_, CheckOutcome::OutOfDate) => &messages.deps_out_of_date,
+                (_, CheckOutcome::Error) => &messages.error_while_comparing_checksums,
+            };
+
+            let in_reply_to = match report.back_in_sync {
+                true => report
+                    .incident
+                    .and_then(|incident| incident.notice_event_id.as_deref()),
+                false => None,
+            };
+
+            let event_id = send_formatted_notice(
+                &self.cfg.matrix,
+                self.client.clone(),
+                &self.cfg.matrix.room_id,
+                &template.render(report.details),
+                in_reply_to,
+            )
+            .await?;
+
+            log::info!("Sent notice to the Matrix room");
+
+            Ok(Some(event_id))
+        })
+    }
+}
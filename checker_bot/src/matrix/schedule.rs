@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use chrono::DateTime;
+use reqwest::Client;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+
+use checker_base::error::Error;
+
+use crate::config::{Config, Schedule};
+use crate::matrix::escalation::process_escalation;
+use crate::queue::CheckQueue;
+use crate::storage::{CheckRequest, SharedStore, State, now_ts};
+
+/// How often the scheduler looks for a scheduled check that is due, or an
+/// incident that needs escalating.
+const TICK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A handle on the scheduler, used by the sync loop to stop it.
+pub(super) struct SchedulerHandle {
+    cancel: watch::Sender<bool>,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl SchedulerHandle {
+    /// Whether the scheduler has stopped by itself, which only happens if it
+    /// hit an I/O error.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stop the scheduler and wait for it to finish, returning the error it
+    /// hit, if any.
+    pub async fn stop(self) -> Result<(), Error> {
+        self.cancel.send_replace(true);
+
+        match self.task.await {
+            Ok(result) => result,
+            Err(err) => panic!("The scheduler panicked: {err}"),
+        }
+    }
+}
+
+/// Start a task which regularly queues scheduled checks and escalates the
+/// ongoing incident, according to the given configuration.
+///
+/// This runs on its own timer, so that scheduled checks and escalations still
+/// happen while the bot can't sync with the Matrix server (e.g. because it is
+/// down). If the handle is dropped, the task stops.
+pub(super) fn spawn_scheduler(
+    config: watch::Receiver<Config>,
+    client: Client,
+    store: SharedStore,
+    queue: CheckQueue,
+) -> SchedulerHandle {
+    let (cancel_tx, mut cancel) = watch::channel(false);
+
+    let task = tokio::spawn(async move {
+        let mut interval = time::interval(TICK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancel.wait_for(|cancelled| *cancelled) => return Ok(()),
+            }
+
+            let cfg = config.borrow().clone();
+            let result = match process_schedule(&cfg, &store, &queue).await {
+                Ok(()) => process_escalation(&cfg, client.clone(), &store).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => {}
+                // An I/O error (when updating the store) is unlikely to go
+                // away by itself.
+                Err(Error::Io(err)) => return Err(Error::Io(err)),
+                Err(err) => log::error!("Error while processing the schedule: {err}"),
+            }
+        }
+    });
+
+    SchedulerHandle {
+        cancel: cancel_tx,
+        task,
+    }
+}
+
+/// Queue a check if one is due according to the schedule in the configuration,
+/// see [`is_due`].
+async fn process_schedule(
+    cfg: &Config,
+    store: &SharedStore,
+    queue: &CheckQueue,
+) -> Result<(), Error> {
+    let Some(schedule) = &cfg.schedule else {
+        return Ok(());
+    };
+
+    let now = now_ts();
+
+    if !is_due(schedule, store.lock().await.state(), now) {
+        return Ok(());
+    }
+
+    log::info!("Scheduled check is due");
+
+    queue.enqueue(CheckRequest::Scheduled).await?;
+
+    store
+        .lock()
+        .await
+        .update(|state| state.last_scheduled_check = Some(now))
+        .await?;
+
+    Ok(())
+}
+
+/// Whether a scheduled check is due at the given time.
+///
+/// A check is due when the schedule has an occurrence between the last check
+/// that tracks the sync status (see
+/// [`crate::storage::Trigger::tracks_sync_status`]) or the last scheduled
+/// check, whichever is the most recent, and now. If no such check has ever run,
+/// a check is due straight away.
+fn is_due(schedule: &Schedule, state: &State, now: u64) -> bool {
+    let last_check = state
+        .check_history
+        .iter()
+        .rfind(|record| record.trigger.tracks_sync_status())
+        .map(|record| record.timestamp)
+        .max(state.last_scheduled_check);
+
+    let Some(last_check) = last_check else {
+        return true;
+    };
+
+    let last_check = DateTime::from_timestamp(last_check as i64, 0).unwrap_or_default();
+    schedule
+        .next_after(last_check)
+        .is_some_and(|due| due.timestamp() <= now as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{CheckOutcome, CheckRecord, Trigger};
+
+    use super::*;
+
+    fn record(timestamp: u64, trigger: Trigger) -> CheckRecord {
+        CheckRecord {
+            id: None,
+            timestamp,
+            trigger,
+            moz_rev: "tip".to_string(),
+            tb_rev: "tip".to_string(),
+            moz_changeset: None,
+            tb_changeset: None,
+            files: vec![],
+            duration_ms: 0,
+            outcome: CheckOutcome::UpToDate,
+            error: None,
+            notice_event_id: None,
+        }
+    }
+
+    fn hourly() -> Schedule {
+        Schedule::Interval(Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn due_straight_away_without_checks() {
+        assert!(is_due(&hourly(), &State::default(), 0));
+    }
+
+    #[test]
+    fn due_once_the_schedule_passes_the_last_check() {
+        let state = State {
+            check_history: vec![record(1000, Trigger::Schedule)],
+            ..Default::default()
+        };
+
+        assert!(!is_due(&hourly(), &state, 4000));
+        assert!(is_due(&hourly(), &state, 4600));
+    }
+
+    #[test]
+    fn last_scheduled_check_counts_even_if_not_recorded() {
+        let state = State {
+            check_history: vec![record(1000, Trigger::Schedule)],
+            last_scheduled_check: Some(3000),
+            ..Default::default()
+        };
+
+        assert!(!is_due(&hourly(), &state, 4600));
+        assert!(is_due(&hourly(), &state, 6600));
+    }
+
+    #[test]
+    fn checks_requested_over_http_dont_count() {
+        let state = State {
+            check_history: vec![
+                record(1000, Trigger::Schedule),
+                record(4000, Trigger::HttpRequest),
+            ],
+            ..Default::default()
+        };
+
+        assert!(is_due(&hourly(), &state, 4600));
+    }
+}
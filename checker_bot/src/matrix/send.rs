@@ -5,29 +5,89 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use reqwest::Client;
+use serde::Deserialize;
 use url::Url;
 
 use checker_base::error::Error;
 
 use crate::config::MatrixConfig;
-use crate::matrix::{MessageEventContent, MessageType};
+#[cfg(feature = "e2ee")]
+use crate::matrix::crypto::encrypt_room_event;
+use crate::matrix::{InReplyTo, Mentions, MessageEventContent, MessageType, RelatesTo};
+use crate::metrics::METRICS;
+use crate::template::RenderedMessage;
 
-/// Send an `m.room.message` event to the target room specified in the
-/// configuration.
+/// A response to a request sending an event to a room.
+///
+/// See <https://spec.matrix.org/v1.14/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid>
+#[derive(Debug, Clone, Deserialize)]
+struct SendResponse {
+    event_id: String,
+}
+
+/// Send an `m.room.message` event to the room with the given ID, and return the
+/// ID of the new event.
 ///
 /// The message's content has `m.notice` as its message type and the provided
-/// text as the body.
+/// text as the body. If encryption support is enabled and the room is
+/// encrypted, the message is sent as an `m.room.encrypted` event instead.
 pub(super) async fn send_notice(
     matrix_cfg: &MatrixConfig,
     client: Client,
+    room_id: &str,
     message: &str,
-) -> Result<(), Error> {
-    // Build and string-ified the event content.
+) -> Result<String, Error> {
+    let message = RenderedMessage {
+        body: message.to_owned(),
+        html_body: None,
+        mentions: vec![],
+    };
+
+    send_formatted_notice(matrix_cfg, client, room_id, &message, None).await
+}
+
+/// Same as [`send_notice`], but with a message rendered from a template, which
+/// might include an HTML version and mentions. If `in_reply_to` is set, the
+/// message is sent as a reply to the event with this ID.
+pub(super) async fn send_formatted_notice(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    room_id: &str,
+    message: &RenderedMessage,
+    in_reply_to: Option<&str>,
+) -> Result<String, Error> {
+    // Build the event content.
     let content = MessageEventContent {
-        body: Some(message.to_owned()),
+        body: Some(message.body.clone()),
         msgtype: Some(MessageType::Notice),
+        format: message
+            .html_body
+            .as_ref()
+            .map(|_| "org.matrix.custom.html".to_string()),
+        formatted_body: message.html_body.clone(),
+        relates_to: in_reply_to.map(|event_id| RelatesTo {
+            in_reply_to: InReplyTo {
+                event_id: event_id.to_string(),
+            },
+        }),
+        mentions: (!message.mentions.is_empty()).then(|| Mentions {
+            user_ids: message.mentions.clone(),
+        }),
     };
-    let content = serde_json::to_string(&content)?;
+    let content = serde_json::to_value(&content)?;
+
+    // Encrypt the message if the room requires it.
+    #[cfg(feature = "e2ee")]
+    let (event_type, content) = encrypt_room_event(
+        matrix_cfg,
+        client.clone(),
+        room_id,
+        "m.room.message",
+        content,
+    )
+    .await?;
+    #[cfg(not(feature = "e2ee"))]
+    let event_type = "m.room.message";
 
     // Use the current timestamp as the transaction ID.
     let now_ts = SystemTime::now()
@@ -38,20 +98,24 @@ pub(super) async fn send_notice(
         .as_millis();
 
     let url = format!(
-        "https://{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
-        matrix_cfg.server_host, matrix_cfg.room_id, now_ts
+        "https://{}/_matrix/client/v3/rooms/{}/send/{}/{}",
+        matrix_cfg.server_host, room_id, event_type, now_ts
     );
     let url = Url::parse(&url)?;
 
     // Send the request, with HTTP errors propagated as Rust errors since no
     // non-2XX response is expected here.
-    client
+    let response: SendResponse = client
         .put(url)
         .bearer_auth(matrix_cfg.access_token.clone())
-        .body(content)
+        .json(&content)
         .send()
         .await?
-        .error_for_status()?;
+        .error_for_status()?
+        .json()
+        .await?;
+
+    METRICS.record_notice_sent();
 
-    Ok(())
+    Ok(response.event_id)
 }
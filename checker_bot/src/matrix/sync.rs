@@ -2,26 +2,36 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::cell::RefCell;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use reqwest::Client;
+use tokio::sync::watch;
 use tokio::time;
 use url::Url;
 
-use checker_base::checksums::verify_checksums_match;
 use checker_base::error::Error;
 
 use crate::config::Config;
+use crate::matrix::account::whoami;
+use crate::matrix::commands::{Command, handle_command};
+use crate::matrix::membership::{join_room, joined_rooms, leave_room};
+use crate::matrix::schedule::spawn_scheduler;
 use crate::matrix::send::send_notice;
-use crate::storage::store_sync_token;
+use crate::matrix::worker::Worker;
+use crate::metrics::METRICS;
+use crate::queue::{CheckQueue, CheckRequests};
+use crate::signals::ShutdownSignal;
+use crate::storage::{CheckRequest, ProcessedPush, QueuedPush, SharedStore, now_ts};
 
 use self::{
-    filter::{EventFilter, RoomFilter, TimelineFilter},
-    response::SyncResponse,
+    filter::{EventFilter, RoomEventFilter, RoomFilter, upload_filter},
+    response::{InvitedRoom, SyncResponse},
 };
 
-use super::MessageType;
+#[cfg(feature = "e2ee")]
+use super::crypto;
+use super::{MessageEventContent, MessageType};
 
 mod filter;
 mod response;
@@ -33,141 +43,495 @@ mod response;
 /// this case, since the default timeout is `0` (as per the Matrix spec).
 const SYNC_TIMEOUT: usize = 30000;
 
+/// A message sent to the Matrix room, decrypted if needed.
+struct Message {
+    event_id: String,
+    sender: String,
+    content: MessageEventContent,
+}
+
 /// Send a sync request and process the response.
 ///
-/// If the response includes messages for a push, download the relevant files
-/// from mozilla-central and compare their checksums to the ones stored in
-/// comm-central, then send the appropriate message to the Matrix room.
+/// Invites are processed first, see [`process_invites`]. Then, if the response
+/// includes commands addressed to the bot, they are executed.
 ///
-/// This happens only once per sync, even if it includes multiple push messages
-/// (since we always compare with repository tips).
-async fn do_sync(cfg: &Config, client: Client, url: Url) -> Result<String, Error> {
-    // Send a new sync request and parse the sync response. Sync responses
-    // should only feature 2XX codes, so propagate an error if we get anything
-    // else.
-    let response: SyncResponse = client
-        .get(url)
-        .bearer_auth(&cfg.matrix.access_token)
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
+/// Messages for mozilla-central and comm-central pushes are added to the check
+/// queue. The check worker debounces them and runs the checks in the background
+/// (see [`Worker`]), so that a long check doesn't delay the next sync.
+///
+/// If a shutdown is requested while waiting for the sync response, the request
+/// is cancelled and this function returns without processing anything.
+async fn do_sync(
+    cfg: &Config,
+    client: Client,
+    store: &SharedStore,
+    queue: &CheckQueue,
+    user_id: &str,
+    url: Url,
+    shutdown: &mut ShutdownSignal,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let response = tokio::select! {
+        response = send_sync_request(cfg, client.clone(), url) => response,
+        _ = shutdown.requested() => {
+            log::info!("Cancelled in-flight sync request");
+            return Ok(());
+        }
+    };
+    match response {
+        Ok(_) => METRICS.record_sync_success(start.elapsed()),
+        Err(_) => METRICS.record_sync_failure(),
+    }
+    let response = response?;
+
+    #[cfg(feature = "e2ee")]
+    crypto::process_sync(
+        &cfg.matrix,
+        client.clone(),
+        &response.device_one_time_keys_count,
+        response.to_device.events.iter().map(|event| {
+            (
+                event.event_type.as_str(),
+                event.sender.as_str(),
+                &event.content,
+            )
+        }),
+    )
+    .await?;
 
     let next_token = response.next_batch;
 
     // If there's no message for us to process, just bail early.
-    let rooms = match response.rooms {
+    let mut rooms = match response.rooms {
         Some(rooms) => rooms,
         None => {
-            store_sync_token(cfg, &next_token).await?;
-            return Ok(next_token);
+            store.lock().await.store_sync_token(&next_token).await?;
+            return Ok(());
         }
     };
 
-    let events = match rooms.join.get(&cfg.matrix.room_id) {
-        Some(room) => &room.timeline.events,
+    process_invites(cfg, client.clone(), user_id, &rooms.invite).await;
+
+    #[cfg(feature = "e2ee")]
+    for (room_id, room) in &rooms.join {
+        let events = room.state.events.iter().chain(&room.timeline.events);
+        crypto::process_room_state(
+            room_id,
+            events
+                .filter(|event| event.state_key.is_some())
+                .map(|event| (event.event_type.as_str(), &event.content)),
+        )
+        .await?;
+    }
+
+    #[cfg_attr(not(feature = "e2ee"), allow(unused_mut))]
+    let mut events = match rooms.join.remove(&cfg.matrix.room_id) {
+        Some(room) => room.timeline.events,
         None => {
-            store_sync_token(cfg, &next_token).await?;
-            return Ok(next_token);
+            store.lock().await.store_sync_token(&next_token).await?;
+            return Ok(());
         }
     };
 
-    // Try to find at least one message matching a push in the sync response.
-    let pushes = events
+    // Decrypt encrypted events before looking at their content. Events that
+    // can't be decrypted are left as they are, and ignored below.
+    #[cfg(feature = "e2ee")]
+    for event in events
+        .iter_mut()
+        .filter(|event| event.event_type == "m.room.encrypted")
+    {
+        let decrypted = crypto::decrypt_room_event(
+            &cfg.matrix.room_id,
+            &event.event_id,
+            &event.sender,
+            &event.content,
+        )
+        .await?;
+        if let Some((event_type, content)) = decrypted {
+            event.event_type = event_type;
+            event.content = content;
+        }
+    }
+
+    // Only keep messages.
+    let messages = events
+        .into_iter()
+        .filter(|event| event.event_type == "m.room.message")
+        .filter_map(|event| {
+            Some(Message {
+                event_id: event.event_id,
+                sender: event.sender,
+                content: serde_json::from_value(event.content).ok()?,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Execute any command sent to the bot. Commands are sent as text messages
+    // rather than notices, and we ignore the ones we've sent ourselves.
+    //
+    // The event content might be empty if the event was redacted. Because it's
+    // empty in this case, and not missing, serde won't deserialize it as
+    // `Option::None`. We could solve this with a custom implementation of
+    // `Deserialize` on `MessageEventContent`, but it's easier, and correct in
+    // practice, to assume that when the message type is present then the body
+    // is too.
+    let commands = messages
         .iter()
-        // The event content might be empty if the event was redacted.
-        // Because it's empty in this case, and not missing, serde won't
-        // deserialize it as `Option::None`. We could solve this with a
-        // custom implementation of `Deserialize` on `MessageEventContent`,
-        // but it's easier, and correct in practice, to assume that when the
-        // message type is present then the body is too.
-        .filter_map(|event| match &event.content.msgtype {
-            Some(MessageType::Notice) => Some(event.content.body.clone().unwrap()),
+        .filter(|message| message.sender != user_id)
+        .filter_map(|message| match &message.content.msgtype {
+            Some(MessageType::Text) => message
+                .content
+                .body
+                .as_deref()
+                .and_then(Command::parse)
+                .map(|command| (message.sender.as_str(), command)),
             _ => None,
         })
-        .filter(|body| body.contains(&cfg.push_message_substring))
         .collect::<Vec<_>>();
 
-    // If there's any push, then compare the checksums and send the appropriate
-    // message.
-    if !pushes.is_empty() {
-        log::info!("Processing new push");
+    // A command failing shouldn't prevent the others from being handled, or the
+    // sync token from being stored, lest the commands be handled again on the
+    // next sync.
+    for (sender, command) in commands {
+        let room_id = &cfg.matrix.room_id;
+        match handle_command(cfg, client.clone(), store, room_id, sender, command).await {
+            Ok(()) => {}
+            // I/O errors come from the store, and are unlikely to go away by
+            // themselves.
+            Err(Error::Io(err)) => return Err(Error::Io(err)),
+            Err(err) => log::error!("Error while handling command from {sender}: {err}"),
+        }
+    }
 
-        let notice_text = match verify_checksums_match(Default::default()).await {
-            Ok(checksums_match) if checksums_match => {
-                log::info!("Checksums match");
-                &cfg.messages.deps_up_to_date
-            }
-            Ok(_) => {
-                log::info!("Checksums do not match");
-                &cfg.messages.deps_out_of_date
-            }
-            Err(err) => {
-                log::error!("Error while comparing checksums: {err}");
-                &cfg.messages.error_while_comparing_checksums
+    // Try to find at least one message matching a push in the sync response.
+    let notices = messages
+        .iter()
+        .filter(|message| matches!(message.content.msgtype, Some(MessageType::Notice)))
+        .filter_map(|message| Some((message, message.content.body.as_deref()?)))
+        .collect::<Vec<_>>();
+
+    let pushes = notices
+        .iter()
+        .filter_map(|(message, body)| {
+            if body.contains(&cfg.push_message_substring) {
+                Some((message.event_id.as_str(), *body, false))
+            } else if cfg
+                .comm_push_message_substring
+                .as_ref()
+                .is_some_and(|substring| body.contains(substring))
+            {
+                Some((message.event_id.as_str(), *body, true))
+            } else {
+                None
             }
-        };
+        })
+        .collect::<Vec<_>>();
+
+    // Hand any push over to the check worker. The latest mozilla-central push
+    // is recorded here so it's accurate even while the check is pending.
+    if let Some((_, body, _)) = pushes.iter().rfind(|(_, _, is_comm_push)| !is_comm_push) {
+        store
+            .lock()
+            .await
+            .update(|state| {
+                state.last_push = Some(ProcessedPush {
+                    timestamp: now_ts(),
+                    message: body.to_string(),
+                })
+            })
+            .await?;
+    }
 
-        send_notice(&cfg.matrix, client.clone(), &notice_text).await?;
+    for (event_id, body, is_comm_push) in pushes {
+        let push = QueuedPush {
+            event_id: event_id.to_string(),
+            body: body.to_string(),
+        };
+        let request = match is_comm_push {
+            false => CheckRequest::Push(push),
+            true => CheckRequest::CommPush(push),
+        };
 
-        log::info!("Sent notice to the Matrix room");
+        queue.enqueue(request).await?;
     }
 
-    store_sync_token(cfg, &next_token).await?;
-    Ok(next_token)
+    store.lock().await.store_sync_token(&next_token).await?;
+    Ok(())
 }
 
-/// Start a never-ending sync loop.
+/// Send a new sync request and parse the sync response.
 ///
-/// Each iteration of the loop sends a sync request and processes its response.
-/// See the documentation for [`do_sync`] for more details.
+/// Sync responses should only feature 2XX codes, so propagate an error if we
+/// get anything else.
+async fn send_sync_request(cfg: &Config, client: Client, url: Url) -> Result<SyncResponse, Error> {
+    let response = client
+        .get(url)
+        .bearer_auth(&cfg.matrix.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(response)
+}
+
+/// Process the rooms the bot has been invited to since the last sync.
+///
+/// An invite is accepted if it was sent by one of the allowed inviters listed
+/// in the configuration, and if it is for a room the bot is configured to
+/// serve. If the configuration includes an introduction message, it is sent to
+/// the room after joining it. Any other invite is rejected.
+///
+/// Failing to process an invite is logged rather than returned, so that it
+/// doesn't prevent the other invites from being processed, or the sync token
+/// from being stored.
+async fn process_invites(
+    cfg: &Config,
+    client: Client,
+    user_id: &str,
+    invites: &HashMap<String, InvitedRoom>,
+) {
+    for (room_id, room) in invites {
+        if let Err(err) = process_invite(cfg, client.clone(), user_id, room_id, room).await {
+            log::error!("Error while processing invite to {room_id}: {err}");
+        }
+    }
+}
+
+/// Accept or reject the invite to the given room, see [`process_invites`].
+async fn process_invite(
+    cfg: &Config,
+    client: Client,
+    user_id: &str,
+    room_id: &str,
+    room: &InvitedRoom,
+) -> Result<(), Error> {
+    // The inviter is the sender of the membership event that changed our
+    // membership to "invite".
+    let inviter = room
+        .invite_state
+        .events
+        .iter()
+        .find(|event| event.event_type == "m.room.member" && event.state_key == user_id)
+        .map(|event| event.sender.as_str());
+
+    let inviter_allowed = inviter.is_some_and(|inviter| {
+        cfg.matrix
+            .allowed_inviters
+            .iter()
+            .any(|user| user == inviter)
+    });
+
+    if !inviter_allowed || !cfg.serves_room(room_id) {
+        log::info!("Rejecting invite to {room_id} from {inviter:?}");
+        return leave_room(&cfg.matrix, client, room_id).await;
+    }
+
+    log::info!("Accepting invite to {room_id} from {inviter:?}");
+    join_room(&cfg.matrix, client.clone(), room_id).await?;
+
+    if let Some(introduction) = &cfg.messages.introduction {
+        send_notice(&cfg.matrix, client, room_id, introduction).await?;
+    }
+
+    Ok(())
+}
+
+/// Leave any room the bot is in but isn't configured to serve, e.g. because it
+/// was removed from the configuration since the last time the bot ran.
+async fn leave_unconfigured_rooms(cfg: &Config, client: Client) -> Result<(), Error> {
+    for room_id in joined_rooms(&cfg.matrix, client.clone()).await? {
+        if !cfg.serves_room(&room_id) {
+            log::info!("Leaving room {room_id} as it is not in the configuration");
+            leave_room(&cfg.matrix, client.clone(), &room_id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Leave unconfigured rooms and upload the sync filter for the given
+/// configuration, then build the base URL for sync requests.
 ///
-/// If an I/O error occurs (when updating the stored sync token), this function
-/// returns with it.
-pub(crate) async fn sync(cfg: &Config, token: String) -> Result<(), Error> {
-    // Filter sync responses for messages in the target room, and limit the
-    // properties returned to the event content itself.
+/// The base URL is essentially the full URL used for syncing, but without the
+/// token since it changes for each sync request.
+async fn prepare_sync(
+    cfg: &Config,
+    client: Client,
+    store: &SharedStore,
+    user_id: &str,
+) -> Result<Url, Error> {
+    leave_unconfigured_rooms(cfg, client.clone()).await?;
+
+    // Filter sync responses for messages in the rooms the bot serves (only the
+    // target room's are processed), and limit the properties returned to the
+    // ones we need to process messages and invites. With encryption enabled,
+    // changes to the membership and encryption state of these rooms are needed
+    // as well.
+    let mut rooms = vec![cfg.matrix.room_id.clone()];
+    if let Some(room) = cfg.escalation.as_ref().and_then(|esc| esc.room.as_ref()) {
+        rooms.push(room.room_id.clone());
+    }
+    let state_types: Vec<String> = vec![
+        #[cfg(feature = "e2ee")]
+        "m.room.encryption".into(),
+        #[cfg(feature = "e2ee")]
+        "m.room.member".into(),
+    ];
+    let mut timeline_types = vec!["m.room.message".to_string()];
+    #[cfg(feature = "e2ee")]
+    timeline_types.push("m.room.encrypted".into());
+    timeline_types.extend(state_types.iter().cloned());
+
     let filter = EventFilter {
-        event_fields: vec!["content".into()],
+        event_fields: vec![
+            "content".into(),
+            "event_id".into(),
+            "sender".into(),
+            "state_key".into(),
+            "type".into(),
+        ],
         room: RoomFilter {
-            timeline: TimelineFilter {
-                rooms: vec![cfg.matrix.room_id.clone()],
-                types: vec!["m.room.message".into()],
+            state: RoomEventFilter {
+                rooms: rooms.clone(),
+                types: state_types,
+            },
+            timeline: RoomEventFilter {
+                rooms,
+                types: timeline_types,
             },
         },
     };
 
-    // Build the base URL with the config and the string-ified filter. This URL
-    // is essentially the full URL used for syncing, but without the token since
-    // it will change for each iteration of the loop.
-    let filter = serde_json::to_string(&filter)?;
+    // Upload the filter to the server (unless we've already done so), so we
+    // only need to reference it by its ID in sync requests.
+    let filter_id = {
+        let mut store = store.lock().await;
+        upload_filter(&cfg.matrix, client, &mut store, user_id, &filter).await?
+    };
+
     let base_url = format!(
         "https://{}/_matrix/client/v3/sync?filter={}&timeout={}",
-        cfg.matrix.server_host, filter, SYNC_TIMEOUT,
+        cfg.matrix.server_host, filter_id, SYNC_TIMEOUT,
     );
-    let base_url = Url::parse(&base_url)?;
 
-    // Loop indefinitely to listen for new messages in the room.
+    Ok(Url::parse(&base_url)?)
+}
+
+/// Start a sync loop, which runs until a shutdown is requested.
+///
+/// Each iteration of the loop sends a sync request and processes its response.
+/// See the documentation for [`do_sync`] for more details. When a shutdown is
+/// requested, an in-flight sync request is cancelled.
+///
+/// Checks run in a separate task (see [`Worker`]), which is started here,
+/// shares the store with the sync loop, and gets check requests from the given
+/// queue. It is stopped when the sync loop stops, after letting the check in
+/// progress (if any) finish (see [`super::worker::WorkerHandle::stop`]).
+/// Scheduled checks and escalations are handled by another task (see
+/// [`spawn_scheduler`]), which is started and stopped along with the worker.
+///
+/// If the configuration is reloaded, it is applied before the next sync
+/// request. The Matrix account and the store location are not affected by a
+/// reload.
+///
+/// If an I/O error occurs (when updating the store), either in the sync loop or
+/// in the check worker or the scheduler, this function returns with it.
+pub(crate) async fn sync(
+    mut config: watch::Receiver<Config>,
+    store: SharedStore,
+    queue: CheckQueue,
+    requests: CheckRequests,
+    mut shutdown: ShutdownSignal,
+) -> Result<(), Error> {
     let client = Client::new();
-    let token = RefCell::new(token);
-    loop {
+    let mut cfg = config.borrow_and_update().clone();
+
+    // Figure out who we are, so we can recognise invites sent to us and ignore
+    // our own messages.
+    let whoami = whoami(&cfg.matrix, client.clone()).await?;
+    let user_id = whoami.user_id;
+    log::info!("Logged in as {user_id}");
+
+    #[cfg(feature = "e2ee")]
+    crypto::init(&cfg, client.clone(), &user_id, whoami.device_id.as_deref()).await?;
+
+    let mut base_url = prepare_sync(&cfg, client.clone(), &store, &user_id).await?;
+
+    // The worker only gets configuration changes once they've been applied to
+    // the sync loop, so that they both use the same configuration.
+    let (worker_config_tx, worker_config) = watch::channel(cfg.clone());
+    let scheduler = spawn_scheduler(
+        worker_config.clone(),
+        client.clone(),
+        store.clone(),
+        queue.clone(),
+    );
+    let worker = Worker::spawn(worker_config, client.clone(), store.clone(), requests);
+
+    // Loop to listen for new messages in the room until we're asked to stop.
+    while !shutdown.is_requested() {
+        // The worker and the scheduler only stop by themselves if they hit an
+        // I/O error.
+        if worker.is_finished() {
+            return worker.stop().await;
+        }
+        if scheduler.is_finished() {
+            return scheduler.stop().await;
+        }
+
+        // Apply the new configuration if it was reloaded since the last sync.
+        // The sender is only dropped when shutting down, in which case there is
+        // nothing left to apply.
+        if config.has_changed().unwrap_or(false) {
+            let new_cfg = config.borrow_and_update().clone();
+            match prepare_sync(&new_cfg, client.clone(), &store, &user_id).await {
+                Ok(url) => {
+                    worker_config_tx.send_replace(new_cfg.clone());
+                    cfg = new_cfg;
+                    base_url = url;
+                    log::info!("Applied reloaded configuration");
+                }
+                Err(Error::Io(err)) => return Err(Error::Io(err)),
+                Err(err) => log::error!(
+                    "Error while applying reloaded configuration, keeping the current one: {err}"
+                ),
+            }
+        }
+
         let mut url = base_url.clone();
 
-        log::debug!("Syncing with token: {}", token.borrow());
-        if !token.borrow().is_empty() {
+        let token = store
+            .lock()
+            .await
+            .state()
+            .sync_token
+            .clone()
+            .unwrap_or_default();
+        log::debug!("Syncing with token: {}", token);
+        if !token.is_empty() {
             // We know there's always a query because we've defined one in the
             // base URL.
             let query = url.query().unwrap();
-            let query = format!("{query}&since={}", token.borrow());
+            let query = format!("{query}&since={token}");
             url.set_query(Some(query.as_str()));
         }
 
         // Send the sync request and process the response.
-        match do_sync(cfg, client.clone(), url).await {
-            Ok(sync_token) => {
-                token.replace(sync_token);
-            }
+        let result = do_sync(
+            &cfg,
+            client.clone(),
+            &store,
+            &queue,
+            &user_id,
+            url,
+            &mut shutdown,
+        )
+        .await;
+
+        match result {
+            Ok(()) => {}
             Err(err) => {
                 match err {
                     // If an I/O error happened (e.g. if the file
@@ -176,12 +540,23 @@ pub(crate) async fn sync(cfg: &Config, token: String) -> Result<(), Error> {
                     // here.
                     Error::Io(_) => return Err(err),
                     _ => {
-                        // When any other error, log it and try again in 30s.
+                        // When any other error, log it and try again in 30s
+                        // (unless we're asked to stop in the meantime).
                         log::error!("Error while processing the last sync: {}", err);
-                        time::sleep(Duration::from_secs(30)).await;
+                        tokio::select! {
+                            _ = time::sleep(Duration::from_secs(30)) => {}
+                            _ = shutdown.requested() => {}
+                        }
                     }
                 }
             }
         }
     }
+
+    // The store is written to disk every time it changes, so the latest sync
+    // token has already been persisted at this point.
+    log::info!("Sync loop stopped");
+
+    let scheduler_result = scheduler.stop().await;
+    worker.stop().await.and(scheduler_result)
 }
@@ -2,7 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use serde::Serialize;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use checker_base::error::Error;
+
+use crate::config::MatrixConfig;
+use crate::storage::{Store, StoredFilter};
 
 /// A stripped-down representation of an `EventFilter` used in sync requests.
 ///
@@ -18,11 +25,71 @@ pub(super) struct EventFilter {
 
 #[derive(Debug, Clone, Serialize)]
 pub(super) struct RoomFilter {
-    pub timeline: TimelineFilter,
+    pub state: RoomEventFilter,
+    pub timeline: RoomEventFilter,
 }
 
+/// A filter on the events of a room's timeline or state.
 #[derive(Debug, Clone, Serialize)]
-pub(super) struct TimelineFilter {
+pub(super) struct RoomEventFilter {
     pub rooms: Vec<String>,
     pub types: Vec<String>,
 }
+
+/// A response to a filter upload request.
+///
+/// See <https://spec.matrix.org/v1.14/client-server-api/#post_matrixclientv3useruseridfilter>
+#[derive(Debug, Clone, Deserialize)]
+struct FilterUploadResponse {
+    filter_id: String,
+}
+
+/// Upload the given filter to the server, and return its ID.
+///
+/// If an identical filter has already been uploaded, the ID from the store is
+/// returned instead.
+pub(super) async fn upload_filter(
+    matrix_cfg: &MatrixConfig,
+    client: Client,
+    store: &mut Store,
+    user_id: &str,
+    filter: &EventFilter,
+) -> Result<String, Error> {
+    let definition = serde_json::to_string(filter)?;
+
+    if let Some(stored) = &store.state().filter
+        && stored.definition == definition
+    {
+        return Ok(stored.id.clone());
+    }
+
+    let url = format!(
+        "https://{}/_matrix/client/v3/user/{}/filter",
+        matrix_cfg.server_host, user_id
+    );
+    let url = Url::parse(&url)?;
+
+    let response: FilterUploadResponse = client
+        .post(url)
+        .bearer_auth(&matrix_cfg.access_token)
+        .body(definition.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    log::info!("Uploaded sync filter with ID {}", response.filter_id);
+
+    let filter_id = response.filter_id.clone();
+    store
+        .update(|state| {
+            state.filter = Some(StoredFilter {
+                id: response.filter_id,
+                definition,
+            })
+        })
+        .await?;
+
+    Ok(filter_id)
+}
@@ -5,8 +5,7 @@
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
-
-use crate::matrix::MessageEventContent;
+use serde_json::Value;
 
 /// A response to a sync request.
 ///
@@ -17,24 +16,95 @@ use crate::matrix::MessageEventContent;
 pub(super) struct SyncResponse {
     pub rooms: Option<Rooms>,
     pub next_batch: String,
+    #[cfg(feature = "e2ee")]
+    #[serde(default)]
+    pub to_device: ToDevice,
+    #[cfg(feature = "e2ee")]
+    #[serde(default)]
+    pub device_one_time_keys_count: HashMap<String, usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(super) struct Rooms {
+    // Each section is omitted by the server if it has nothing in it, e.g. if
+    // the sync response only includes new invites.
+    #[serde(default)]
     pub join: HashMap<String, JoinedRoom>,
+    #[serde(default)]
+    pub invite: HashMap<String, InvitedRoom>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(super) struct JoinedRoom {
+    /// The state changes that happened before the timeline, if the timeline
+    /// doesn't cover everything since the last sync.
+    #[cfg(feature = "e2ee")]
+    #[serde(default)]
+    pub state: RoomState,
     pub timeline: RoomTimeline,
 }
 
+#[cfg(feature = "e2ee")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct RoomState {
+    pub events: Vec<RoomEvent>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(super) struct RoomTimeline {
-    pub events: Vec<MessageEvent>,
+    pub events: Vec<RoomEvent>,
+}
+
+/// An event in a room's timeline.
+///
+/// The content is kept as a raw JSON value, since it might need to be
+/// decrypted before it can be deserialized into the right type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct RoomEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub event_id: String,
+    pub sender: String,
+    /// Only set for state events.
+    #[serde(default)]
+    pub state_key: Option<String>,
+    pub content: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct InvitedRoom {
+    pub invite_state: InviteState,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct InviteState {
+    pub events: Vec<StrippedStateEvent>,
+}
+
+/// A stripped-down state event, as included in the state of a room the bot has
+/// been invited to.
+///
+/// The content is not deserialized, since we only need to know who sent the
+/// `m.room.member` event that invited us.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct StrippedStateEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub state_key: String,
+    pub sender: String,
+}
+
+#[cfg(feature = "e2ee")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct ToDevice {
+    pub events: Vec<ToDeviceEvent>,
 }
 
+#[cfg(feature = "e2ee")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub(super) struct MessageEvent {
-    pub content: MessageEventContent,
+pub(super) struct ToDeviceEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub sender: String,
+    pub content: Value,
 }
@@ -0,0 +1,374 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use reqwest::Client;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use checker_base::error::Error;
+
+use crate::config::Config;
+use crate::matrix::check::{extract_push_ids, process_comm_push, run_check};
+use crate::queue::CheckRequests;
+use crate::storage::{CheckRequest, ManualCheck, PulsePush, QueuedPush, SharedStore, Trigger};
+
+/// The requests received during a debounce window.
+#[derive(Debug, Default)]
+struct Batch {
+    pushes: Vec<QueuedPush>,
+    last_comm_push: Option<QueuedPush>,
+    scheduled: bool,
+    manual: Vec<ManualCheck>,
+    pulse_pushes: Vec<PulsePush>,
+
+    /// The number of requests in the batch. Since requests are handed over to
+    /// the worker in the order they're queued in, these are the first requests
+    /// in the store's queue.
+    len: usize,
+}
+
+impl Batch {
+    fn add(&mut self, request: CheckRequest) {
+        self.len += 1;
+
+        match request {
+            CheckRequest::Push(push) => self.pushes.push(push),
+            CheckRequest::CommPush(push) => self.last_comm_push = Some(push),
+            CheckRequest::Scheduled => self.scheduled = true,
+            CheckRequest::Manual(check) => self.manual.push(check),
+            CheckRequest::PulsePush(push) => self.pulse_pushes.push(push),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A handle on the check worker, used by the sync loop to stop it.
+pub(super) struct WorkerHandle {
+    cancel: watch::Sender<bool>,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl WorkerHandle {
+    /// Whether the worker has stopped by itself, which only happens if it hit
+    /// an I/O error.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stop the worker and wait for it to finish, returning the error it hit,
+    /// if any.
+    ///
+    /// Checks that are already running are allowed to finish, for up to the
+    /// configured check timeout. Requests whose check hasn't started stay in
+    /// the store's queue, and are processed on the next start.
+    pub async fn stop(self) -> Result<(), Error> {
+        self.cancel.send_replace(true);
+
+        match self.task.await {
+            Ok(result) => result,
+            Err(err) => panic!("The check worker panicked: {err}"),
+        }
+    }
+}
+
+/// The check worker, which processes check requests in the background so that
+/// checks don't hold up the sync loop.
+pub(super) struct Worker {
+    requests: mpsc::Receiver<CheckRequest>,
+    config: watch::Receiver<Config>,
+    client: Client,
+    store: SharedStore,
+}
+
+impl Worker {
+    /// Start the worker in a new task.
+    ///
+    /// The worker gets requests from the given end of the check queue (see
+    /// [`crate::queue::CheckQueue`]). It starts by processing the requests
+    /// left in the store's queue by the previous run of the bot, if any.
+    pub fn spawn(
+        config: watch::Receiver<Config>,
+        client: Client,
+        store: SharedStore,
+        requests: CheckRequests,
+    ) -> WorkerHandle {
+        let (cancel_tx, cancel) = watch::channel(false);
+
+        let worker = Worker {
+            requests: requests.receiver,
+            config,
+            client,
+            store,
+        };
+
+        WorkerHandle {
+            cancel: cancel_tx,
+            task: tokio::spawn(worker.run(requests.resumed, cancel)),
+        }
+    }
+
+    /// Wait until the worker is asked to stop. If the handle is dropped without
+    /// stopping the worker, this waits forever.
+    async fn cancelled(cancel: &mut watch::Receiver<bool>) {
+        if cancel.wait_for(|cancelled| *cancelled).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Process the given requests resumed from the previous run of the bot,
+    /// then the ones received, until the worker is asked to stop.
+    ///
+    /// Requests are debounced: after receiving a request, the worker waits
+    /// until no new request has been received for the configured debounce
+    /// window, then runs a single check covering all of them.
+    async fn run(
+        mut self,
+        resumed: Vec<CheckRequest>,
+        mut cancel: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        let mut batch = Batch::default();
+        for request in resumed {
+            batch.add(request);
+        }
+
+        if !batch.is_empty() {
+            log::info!(
+                "Resuming {} check request(s) queued before the last stop",
+                batch.len,
+            );
+        }
+
+        loop {
+            if *cancel.borrow() {
+                return Ok(());
+            }
+
+            if batch.is_empty() {
+                tokio::select! {
+                    request = self.requests.recv() => match request {
+                        Some(request) => batch.add(request),
+                        None => return Ok(()),
+                    },
+                    _ = Self::cancelled(&mut cancel) => return Ok(()),
+                }
+            }
+
+            let debounce = Duration::from_secs(self.config.borrow().check_debounce_seconds);
+
+            loop {
+                tokio::select! {
+                    request = self.requests.recv() => match request {
+                        Some(request) => batch.add(request),
+                        None => break,
+                    },
+                    _ = time::sleep(debounce) => break,
+                    _ = Self::cancelled(&mut cancel) => return Ok(()),
+                }
+            }
+
+            let current = std::mem::take(&mut batch);
+            let len = current.len;
+
+            // Each check in the batch has its own timeout, see `run_check`. If
+            // we're asked to stop in the meantime, let the batch finish, so its
+            // notices aren't sent again on the next start, but don't wait for
+            // longer than a check could take.
+            let batch = self.process_batch(current);
+            tokio::pin!(batch);
+            let result = tokio::select! {
+                result = &mut batch => result,
+                _ = Self::cancelled(&mut cancel) => {
+                    let timeout =
+                        Duration::from_secs(self.config.borrow().check_timeout_seconds);
+                    log::info!("Waiting for the check in progress to finish before stopping");
+
+                    match time::timeout(timeout, &mut batch).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            log::warn!(
+                                "Check still running after {}s, it will run again on the next start",
+                                timeout.as_secs()
+                            );
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+
+            match result {
+                Ok(()) => {}
+                // I/O errors come from the store, and are unlikely to go away
+                // by themselves.
+                Err(Error::Io(err)) => return Err(Error::Io(err)),
+                Err(err) => log::error!("Error while processing pushes: {err}"),
+            }
+
+            // Whatever the outcome of the check, don't run it again.
+            self.store
+                .lock()
+                .await
+                .update(|state| {
+                    state.check_queue.drain(..len.min(state.check_queue.len()));
+                })
+                .await?;
+        }
+    }
+
+    /// Run the checks for the requests in the batch.
+    ///
+    /// Manual checks are run first, each on its own since they're for specific
+    /// revisions. Then, a single check of the newest head covers the pushes
+    /// announced on Pulse, unless a newer push was already checked. Finally, a
+    /// single check of the repository tips covers all the other requests: a
+    /// mozilla-central push takes precedence over a scheduled check, which
+    /// takes precedence over a comm-central push, since the resulting check
+    /// also tells us whether the dependencies are back in sync.
+    async fn process_batch(&self, batch: Batch) -> Result<(), Error> {
+        let cfg = self.config.borrow().clone();
+
+        for check in batch.manual {
+            log::info!("Running check {} requested over HTTP", check.id);
+
+            let change_set = cfg.change_set(Some(check.moz_rev), check.tb_rev);
+
+            match run_check(
+                &cfg,
+                self.client.clone(),
+                &self.store,
+                Trigger::HttpRequest,
+                change_set,
+                Some(check.id),
+                vec![],
+            )
+            .await
+            {
+                Ok(()) => {}
+                Err(Error::Io(err)) => return Err(Error::Io(err)),
+                Err(err) => log::error!("Error while running check {}: {err}", check.id),
+            }
+        }
+
+        if let Some(newest) = batch.pulse_pushes.iter().max_by_key(|push| push.push_id) {
+            // Don't let a push that was delivered late override the result of
+            // a check of a newer push.
+            let last_checked = self.last_checked_pulse_push().await;
+
+            if last_checked.is_some_and(|push_id| push_id >= newest.push_id) {
+                log::info!(
+                    "Ignoring Pulse push(es) up to {}, since push {} was already checked",
+                    newest.push_id,
+                    last_checked.unwrap_or_default()
+                );
+            } else {
+                log::info!(
+                    "Checking push {} announced on Pulse, covering {} push(es)",
+                    newest.push_id,
+                    batch.pulse_pushes.len()
+                );
+
+                let mut push_ids = batch
+                    .pulse_pushes
+                    .iter()
+                    .map(|push| push.push_id)
+                    .filter(|push_id| last_checked.is_none_or(|last| *push_id > last))
+                    .collect::<Vec<_>>();
+                push_ids.sort_unstable();
+                push_ids.dedup();
+
+                let change_set = cfg.change_set(Some(newest.rev.clone()), None);
+
+                match run_check(
+                    &cfg,
+                    self.client.clone(),
+                    &self.store,
+                    Trigger::PulsePush {
+                        push_id: newest.push_id,
+                    },
+                    change_set,
+                    None,
+                    push_ids.iter().map(ToString::to_string).collect(),
+                )
+                .await
+                {
+                    Ok(()) => {}
+                    Err(Error::Io(err)) => return Err(Error::Io(err)),
+                    Err(err) => {
+                        log::error!("Error while checking push {}: {err}", newest.push_id)
+                    }
+                }
+            }
+        }
+
+        if let Some(last_push) = batch.pushes.last() {
+            log::info!("Processing {} new push(es)", batch.pushes.len());
+
+            let trigger = Trigger::PushNotice {
+                event_id: Some(last_push.event_id.clone()),
+            };
+            let bodies = batch
+                .pushes
+                .iter()
+                .map(|push| push.body.as_str())
+                .collect::<Vec<_>>();
+            let push_ids = extract_push_ids(&cfg, &bodies);
+
+            run_check(
+                &cfg,
+                self.client.clone(),
+                &self.store,
+                trigger,
+                cfg.change_set(None, None),
+                None,
+                push_ids,
+            )
+            .await
+        } else if batch.scheduled {
+            log::info!("Running scheduled check");
+
+            run_check(
+                &cfg,
+                self.client.clone(),
+                &self.store,
+                Trigger::Schedule,
+                cfg.change_set(None, None),
+                None,
+                vec![],
+            )
+            .await
+        } else if let Some(comm_push) = &batch.last_comm_push {
+            process_comm_push(
+                &cfg,
+                self.client.clone(),
+                &self.store,
+                &comm_push.event_id,
+                &comm_push.body,
+            )
+            .await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The ID of the newest push announced on Pulse that was checked, if any
+    /// is still in the check history.
+    async fn last_checked_pulse_push(&self) -> Option<u64> {
+        self.store
+            .lock()
+            .await
+            .state()
+            .check_history
+            .iter()
+            .filter_map(|record| match record.trigger {
+                Trigger::PulsePush { push_id } => Some(push_id),
+                _ => None,
+            })
+            .max()
+    }
+}
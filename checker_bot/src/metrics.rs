@@ -0,0 +1,211 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use crate::storage::{CheckOutcome, now_ts};
+
+/// The upper bounds of the buckets used for duration histograms, in seconds.
+const DURATION_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// The bot's metrics.
+///
+/// This is a global so that metrics can be recorded from anywhere without
+/// having to pass a handle around.
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// A histogram of durations, in the format Prometheus expects.
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// The number of observations in each bucket of [`DURATION_BUCKETS`].
+    /// Buckets are not cumulative here, they are summed up when rendering.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(idx) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[idx] += 1;
+        }
+
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    /// Render the histogram in the Prometheus text format, using the given
+    /// metric name and (possibly empty) label string, e.g. `path="Cargo.lock"`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let (separator, braced_labels) = match labels.is_empty() {
+            true => ("", String::new()),
+            false => (",", format!("{{{labels}}}")),
+        };
+
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let _ = writeln!(out, "{name}_sum{braced_labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{braced_labels} {}", self.count);
+    }
+}
+
+/// The metrics recorded by the bot, exposed in the Prometheus text format.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    sync_duration: Mutex<Histogram>,
+    sync_failures: AtomicU64,
+    last_successful_sync: AtomicU64,
+    checks_up_to_date: AtomicU64,
+    checks_out_of_date: AtomicU64,
+    checks_error: AtomicU64,
+    hg_fetch_duration: Mutex<BTreeMap<String, Histogram>>,
+    notices_sent: AtomicU64,
+}
+
+impl Metrics {
+    /// Record a successful sync request.
+    pub fn record_sync_success(&self, duration: Duration) {
+        self.sync_duration.lock().unwrap().observe(duration);
+        self.last_successful_sync.store(now_ts(), Ordering::Relaxed);
+    }
+
+    /// Record a failed sync request.
+    pub fn record_sync_failure(&self) {
+        self.sync_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of a check.
+    pub fn record_check(&self, outcome: CheckOutcome) {
+        let counter = match outcome {
+            CheckOutcome::UpToDate => &self.checks_up_to_date,
+            CheckOutcome::OutOfDate => &self.checks_out_of_date,
+            CheckOutcome::Error => &self.checks_error,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long it took to download the given file from hg.
+    pub fn record_hg_fetch(&self, path: &str, duration: Duration) {
+        self.hg_fetch_duration
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Record a notice being sent to a Matrix room.
+    pub fn record_notice_sent(&self) {
+        self.notices_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// When the last successful sync happened, in seconds since the UNIX
+    /// epoch, or `None` if there hasn't been one since the bot started.
+    pub fn last_successful_sync(&self) -> Option<u64> {
+        match self.last_successful_sync.load(Ordering::Relaxed) {
+            0 => None,
+            ts => Some(ts),
+        }
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP tbrust_sync_duration_seconds Duration of Matrix sync requests."
+        );
+        let _ = writeln!(out, "# TYPE tbrust_sync_duration_seconds histogram");
+        self.sync_duration
+            .lock()
+            .unwrap()
+            .render(&mut out, "tbrust_sync_duration_seconds", "");
+
+        let _ = writeln!(
+            out,
+            "# HELP tbrust_sync_failures_total Number of failed Matrix sync requests."
+        );
+        let _ = writeln!(out, "# TYPE tbrust_sync_failures_total counter");
+        let _ = writeln!(
+            out,
+            "tbrust_sync_failures_total {}",
+            self.sync_failures.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP tbrust_seconds_since_last_successful_sync Time since the last successful Matrix sync."
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE tbrust_seconds_since_last_successful_sync gauge"
+        );
+        if let Some(last_sync) = self.last_successful_sync() {
+            let _ = writeln!(
+                out,
+                "tbrust_seconds_since_last_successful_sync {}",
+                now_ts().saturating_sub(last_sync)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP tbrust_checks_total Number of checks performed, by outcome."
+        );
+        let _ = writeln!(out, "# TYPE tbrust_checks_total counter");
+        for (outcome, counter) in [
+            ("up_to_date", &self.checks_up_to_date),
+            ("out_of_date", &self.checks_out_of_date),
+            ("error", &self.checks_error),
+        ] {
+            let _ = writeln!(
+                out,
+                "tbrust_checks_total{{outcome=\"{outcome}\"}} {}",
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP tbrust_hg_fetch_duration_seconds Duration of file downloads from hg, by file."
+        );
+        let _ = writeln!(out, "# TYPE tbrust_hg_fetch_duration_seconds histogram");
+        for (path, histogram) in self.hg_fetch_duration.lock().unwrap().iter() {
+            let labels = format!("path=\"{path}\"");
+            histogram.render(&mut out, "tbrust_hg_fetch_duration_seconds", &labels);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP tbrust_notices_sent_total Number of notices sent to Matrix rooms."
+        );
+        let _ = writeln!(out, "# TYPE tbrust_notices_sent_total counter");
+        let _ = writeln!(
+            out,
+            "tbrust_notices_sent_total {}",
+            self.notices_sent.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
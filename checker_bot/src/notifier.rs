@@ -0,0 +1,82 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::pin::Pin;
+
+use anyhow::Error;
+
+use checker_base::checksums::FileCheckResult;
+use checker_base::revision::ResolvedRev;
+
+use crate::storage::{CheckOutcome, Incident, Trigger};
+use crate::template::CheckDetails;
+
+pub(crate) mod email;
+pub(crate) mod webhook;
+
+/// The future returned by [`Notifier::notify`].
+pub(crate) type NotifyFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Option<String>, Error>> + Send + 'a>>;
+
+/// The result of a check, as given to notifiers.
+#[derive(Debug, Clone)]
+pub(crate) struct CheckReport<'a> {
+    /// When the check completed, as a UNIX timestamp in seconds.
+    pub timestamp: u64,
+
+    pub trigger: &'a Trigger,
+    pub outcome: CheckOutcome,
+
+    /// The checksums of every file that was checked.
+    pub files: &'a [FileCheckResult],
+
+    /// The changesets the mozilla-central and comm-central revisions resolved
+    /// to, unless an error prevented the check from completing.
+    pub moz_changeset: Option<&'a ResolvedRev>,
+    pub tb_changeset: Option<&'a ResolvedRev>,
+
+    /// The details used to fill in message templates.
+    pub details: &'a CheckDetails,
+
+    /// The incident the dependencies were out of sync in before the check, if
+    /// any.
+    pub incident: Option<&'a Incident>,
+
+    /// Whether the dependencies are back in sync after having been out of sync.
+    pub back_in_sync: bool,
+
+    /// Whether the check warrants a message to humans, e.g. in the Matrix
+    /// room. This depends on what triggered the check and on its outcome.
+    pub notable: bool,
+}
+
+impl CheckReport<'_> {
+    /// Whether the check changed the sync status of the dependencies, i.e. they
+    /// went out of sync, or they're back in sync.
+    ///
+    /// Checks that failed to complete, or that don't track the sync status
+    /// (see [`Trigger::tracks_sync_status`]), don't change it.
+    pub fn changed_status(&self) -> bool {
+        if !self.trigger.tracks_sync_status() {
+            return false;
+        }
+
+        match self.outcome {
+            CheckOutcome::UpToDate => self.back_in_sync,
+            CheckOutcome::OutOfDate => self.incident.is_none(),
+            CheckOutcome::Error => false,
+        }
+    }
+}
+
+/// Something that gets told about the result of each check.
+pub(crate) trait Notifier: Send + Sync {
+    /// A short description of the notifier, used in logs.
+    fn name(&self) -> String;
+
+    /// Tell the notifier about the result of a check.
+    ///
+    /// Returns the ID of the Matrix event the notifier sent, if any.
+    fn notify<'a>(&'a self, report: &'a CheckReport<'a>) -> NotifyFuture<'a>;
+}
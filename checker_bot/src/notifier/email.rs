@@ -0,0 +1,120 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use anyhow::Error;
+use lettre::message::{Message, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+use crate::config::{EmailConfig, MessagesConfig};
+use crate::notifier::{CheckReport, Notifier, NotifyFuture};
+use crate::storage::CheckOutcome;
+use crate::template::escape_html;
+
+/// A notifier sending an email when the sync status of the dependencies
+/// changes, i.e. when they go out of sync and when they're back in sync.
+///
+/// The email has a plain text and an HTML part. Both start with the message
+/// that is sent to the Matrix room, followed by the checksums of each file.
+pub(crate) struct EmailNotifier<'a> {
+    cfg: &'a EmailConfig,
+    messages: &'a MessagesConfig,
+}
+
+impl<'a> EmailNotifier<'a> {
+    pub fn new(cfg: &'a EmailConfig, messages: &'a MessagesConfig) -> EmailNotifier<'a> {
+        EmailNotifier { cfg, messages }
+    }
+
+    /// Build and send the email for the given check, regardless of whether it
+    /// changed the sync status.
+    pub async fn send(&self, report: &CheckReport<'_>) -> Result<(), Error> {
+        let (subject, template) = match report.outcome {
+            CheckOutcome::UpToDate => (
+                "comm-central Rust dependencies are back in sync",
+                self.messages
+                    .deps_back_in_sync
+                    .as_ref()
+                    .unwrap_or(&self.messages.deps_up_to_date),
+            ),
+            CheckOutcome::OutOfDate => (
+                "comm-central Rust dependencies are out of date",
+                &self.messages.deps_out_of_date,
+            ),
+            CheckOutcome::Error => (
+                "Error while checking comm-central Rust dependencies",
+                &self.messages.error_while_comparing_checksums,
+            ),
+        };
+
+        let message = template.render(report.details);
+
+        let mut plain = format!("{}\n\nmozilla-central files:\n", message.body);
+        let mut html = format!(
+            "<p>{}</p>\n<p>mozilla-central files:</p>\n<ul>\n",
+            message.html()
+        );
+        for file in report.files {
+            let status = match file.matches() {
+                true => "matches",
+                false => "does not match",
+            };
+
+            plain.push_str(&format!(
+                "  * {}: {status} (expected {}, got {})\n",
+                file.path, file.expected_checksum, file.actual_checksum,
+            ));
+            html.push_str(&format!(
+                "<li><code>{}</code>: {status} (expected <code>{}</code>, got <code>{}</code>)</li>\n",
+                escape_html(&file.path),
+                escape_html(&file.expected_checksum),
+                escape_html(&file.actual_checksum),
+            ));
+        }
+        html.push_str("</ul>\n");
+
+        let mut builder = Message::builder()
+            .from(self.cfg.from.clone())
+            .subject(format!(
+                "{subject} (mozilla-central {})",
+                report.details.moz_rev
+            ));
+        for recipient in &self.cfg.to {
+            builder = builder.to(recipient.clone());
+        }
+        let email = builder.multipart(MultiPart::alternative_plain_html(plain, html))?;
+
+        let mut transport = match self.cfg.starttls {
+            true => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.cfg.smtp_host)?,
+            false => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.cfg.smtp_host),
+        }
+        .port(self.cfg.smtp_port);
+
+        if let (Some(username), Some(password)) = (&self.cfg.username, &self.cfg.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        transport.build().send(email).await?;
+
+        log::info!("Sent email to {} recipient(s)", self.cfg.to.len());
+
+        Ok(())
+    }
+}
+
+impl Notifier for EmailNotifier<'_> {
+    fn name(&self) -> String {
+        format!("email via {}", self.cfg.smtp_host)
+    }
+
+    fn notify<'a>(&'a self, report: &'a CheckReport<'a>) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            if report.changed_status() {
+                self.send(report).await?;
+            }
+
+            Ok(None)
+        })
+    }
+}
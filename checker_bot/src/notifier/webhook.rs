@@ -0,0 +1,207 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::time;
+
+use checker_base::checksums::FileCheckResult;
+use checker_base::revision::ResolvedRev;
+
+use crate::config::WebhookConfig;
+use crate::notifier::{CheckReport, Notifier, NotifyFuture};
+use crate::storage::{CheckOutcome, Trigger};
+
+/// The header holding the signature of the payload.
+const SIGNATURE_HEADER: &str = "X-Checker-Signature";
+
+/// How long to wait before retrying a failed delivery for the first time. The
+/// delay doubles after each attempt.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The payload posted to webhooks. See the README file for its documentation.
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    timestamp: u64,
+    trigger: &'a Trigger,
+    outcome: CheckOutcome,
+    back_in_sync: bool,
+    moz_rev: &'a str,
+    comm_rev: &'a str,
+    moz_changeset: Option<&'a ResolvedRev>,
+    comm_changeset: Option<&'a ResolvedRev>,
+    files: Vec<WebhookFile<'a>>,
+    changed_crates: Option<&'a [String]>,
+    push_ids: &'a [String],
+    error: Option<&'a str>,
+    duration_ms: u64,
+}
+
+/// The result of checking a single file, in a [`WebhookPayload`].
+#[derive(Debug, Serialize)]
+struct WebhookFile<'a> {
+    path: &'a str,
+    expected_checksum: &'a str,
+    actual_checksum: &'a str,
+    matches: bool,
+    size: u64,
+    content_type: Option<&'a str>,
+}
+
+impl<'a> From<&'a FileCheckResult> for WebhookFile<'a> {
+    fn from(file: &'a FileCheckResult) -> Self {
+        WebhookFile {
+            path: &file.path,
+            expected_checksum: &file.expected_checksum,
+            actual_checksum: &file.actual_checksum,
+            matches: file.matches(),
+            size: file.size,
+            content_type: file.content_type.as_deref(),
+        }
+    }
+}
+
+/// A notifier posting the result of every check as JSON to a URL.
+///
+/// The payload is signed with HMAC-SHA256 using the configured secret, and the
+/// signature is sent in the `X-Checker-Signature` header, as `sha256=` followed
+/// by the hex-encoded signature.
+///
+/// Payloads are delivered in the background, so that a slow or failing webhook
+/// doesn't hold up the check. Failed deliveries are retried with an exponential
+/// backoff, unless the server rejected the payload with a 4xx status code
+/// (other than 429).
+pub(crate) struct WebhookNotifier<'a> {
+    cfg: &'a WebhookConfig,
+    client: Client,
+}
+
+impl<'a> WebhookNotifier<'a> {
+    pub fn new(cfg: &'a WebhookConfig, client: Client) -> WebhookNotifier<'a> {
+        WebhookNotifier { cfg, client }
+    }
+}
+
+impl Notifier for WebhookNotifier<'_> {
+    fn name(&self) -> String {
+        format!("webhook {}", self.cfg.url)
+    }
+
+    fn notify<'a>(&'a self, report: &'a CheckReport<'a>) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            let details = report.details;
+            let payload = WebhookPayload {
+                timestamp: report.timestamp,
+                trigger: report.trigger,
+                outcome: report.outcome,
+                back_in_sync: report.back_in_sync,
+                moz_rev: &details.moz_rev,
+                comm_rev: &details.comm_rev,
+                moz_changeset: report.moz_changeset,
+                comm_changeset: report.tb_changeset,
+                files: report.files.iter().map(WebhookFile::from).collect(),
+                changed_crates: details.changed_crates.as_deref(),
+                push_ids: &details.push_ids,
+                error: details.error.as_deref(),
+                duration_ms: details.duration.as_millis() as u64,
+            };
+
+            let body = serde_json::to_vec(&payload)?;
+            let signature = format!("sha256={}", sign(&self.cfg.secret, &body));
+
+            tokio::spawn(deliver_with_retries(
+                self.cfg.clone(),
+                self.client.clone(),
+                body,
+                signature,
+            ));
+
+            Ok(None)
+        })
+    }
+}
+
+/// Send the given body to the webhook, retrying failed deliveries, and log the
+/// error if it can't be delivered.
+async fn deliver_with_retries(
+    cfg: WebhookConfig,
+    client: Client,
+    body: Vec<u8>,
+    signature: String,
+) {
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let err = match deliver(&cfg, &client, &body, &signature).await {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+
+        let retryable = err.status().is_none_or(|status| {
+            !status.is_client_error() || status == StatusCode::TOO_MANY_REQUESTS
+        });
+
+        if !retryable || attempt >= cfg.max_attempts {
+            log::error!("Error while notifying webhook {}: {err}", cfg.url);
+            return;
+        }
+
+        log::warn!(
+            "Delivery to webhook {} failed (attempt {attempt}), retrying in {}s: {err}",
+            cfg.url,
+            delay.as_secs(),
+        );
+
+        time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Send the given body to the webhook, once.
+async fn deliver(
+    cfg: &WebhookConfig,
+    client: &Client,
+    body: &[u8],
+    signature: &str,
+) -> Result<(), reqwest::Error> {
+    client
+        .post(cfg.url.clone())
+        .timeout(Duration::from_secs(cfg.timeout_seconds))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .body(body.to_vec())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Compute the hex-encoded HMAC-SHA256 of the given body.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC should accept keys of any length");
+    mac.update(body);
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_computes_hmac_sha256() {
+        // Test case 2 from RFC 4231.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use anyhow::{Context, Error, bail};
+use futures_util::StreamExt;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, QueueBindOptions, QueueDeclareOptions,
+};
+use lapin::types::FieldTable;
+use lapin::{Connection, ConnectionProperties};
+use serde::Deserialize;
+use tokio::time;
+
+use crate::config::PulseConfig;
+use crate::queue::CheckQueue;
+use crate::storage::{CheckRequest, PulsePush};
+
+/// The tag the bot identifies its consumer with.
+const CONSUMER_TAG: &str = "checker_bot";
+
+/// The type of the hgpush messages announcing new pushes.
+const CHANGEGROUP_MESSAGE_TYPE: &str = "changegroup.1";
+
+/// The longest time to wait before trying to reconnect to Pulse.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// A message from Pulse.
+#[derive(Debug, Deserialize)]
+struct PulseMessage {
+    payload: HgPushPayload,
+}
+
+/// The payload of a message on the hgpush exchange. Its data depends on its
+/// type, and is only parsed for messages announcing new pushes.
+#[derive(Debug, Deserialize)]
+struct HgPushPayload {
+    #[serde(rename = "type")]
+    kind: String,
+    data: serde_json::Value,
+}
+
+/// The data of a message announcing new pushes.
+#[derive(Debug, Deserialize)]
+struct ChangegroupData {
+    heads: Vec<String>,
+    pushlog_pushes: Vec<PushlogPush>,
+}
+
+/// A push in a message announcing new pushes.
+#[derive(Debug, Deserialize)]
+struct PushlogPush {
+    pushid: u64,
+}
+
+/// Consume the messages announcing new pushes from Pulse, and queue a check
+/// for each of them.
+///
+/// This never returns: if the connection fails, or the server cancels the
+/// consumer, it reconnects after a delay that doubles after each failed
+/// attempt, up to [`MAX_RECONNECT_DELAY`].
+pub(crate) async fn consume(cfg: PulseConfig, queue: CheckQueue) {
+    let initial_delay = Duration::from_secs(cfg.reconnect_delay_seconds);
+    let mut delay = initial_delay;
+
+    loop {
+        let mut connected = false;
+
+        match consume_once(&cfg, &queue, &mut connected).await {
+            Ok(()) => log::warn!("Pulse consumer was cancelled by the server"),
+            Err(err) => log::error!("Error while consuming Pulse messages: {err:#}"),
+        }
+
+        // Only back off further if we couldn't get to consuming messages.
+        if connected {
+            delay = initial_delay;
+        }
+
+        log::info!("Reconnecting to Pulse in {}s", delay.as_secs());
+        time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Connect to Pulse, bind the queue to the exchange and consume messages from
+/// it until the connection fails or the consumer is cancelled.
+///
+/// `connected` is set once messages start being consumed.
+async fn consume_once(
+    cfg: &PulseConfig,
+    queue: &CheckQueue,
+    connected: &mut bool,
+) -> Result<(), Error> {
+    let connection = Connection::connect(&cfg.url, ConnectionProperties::default())
+        .await
+        .context("connecting to Pulse")?;
+    let channel = connection
+        .create_channel()
+        .await
+        .context("creating channel")?;
+
+    // The queue is durable so that pushes announced while the bot is stopped
+    // are still checked once it's back.
+    channel
+        .queue_declare(
+            &cfg.queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+        .with_context(|| format!("declaring queue {}", cfg.queue))?;
+    channel
+        .queue_bind(
+            &cfg.queue,
+            &cfg.exchange,
+            &cfg.routing_key,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .with_context(|| format!("binding queue {} to {}", cfg.queue, cfg.exchange))?;
+
+    let mut consumer = channel
+        .basic_consume(
+            &cfg.queue,
+            CONSUMER_TAG,
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .context("starting consumer")?;
+
+    *connected = true;
+    log::info!(
+        "Consuming messages from {} with routing key {}",
+        cfg.exchange,
+        cfg.routing_key
+    );
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery.context("receiving message")?;
+
+        match parse_message(&delivery.data) {
+            Ok(Some(push)) => {
+                log::info!("Pulse announced push {} ({})", push.push_id, push.rev);
+
+                // If queueing the check fails, the message isn't acknowledged,
+                // so it's delivered again after reconnecting.
+                let queued = queue
+                    .enqueue(CheckRequest::PulsePush(push))
+                    .await
+                    .context("queueing check")?;
+
+                // If the check queue is full, hand the message back to the
+                // server and reconnect after a delay, rather than have it
+                // delivered again straight away.
+                if !queued {
+                    delivery
+                        .nack(BasicNackOptions {
+                            requeue: true,
+                            ..Default::default()
+                        })
+                        .await
+                        .context("rejecting message")?;
+                    bail!("the check queue is full");
+                }
+            }
+            Ok(None) => log::debug!("Ignoring Pulse message that doesn't announce a push"),
+            Err(err) => log::warn!("Ignoring invalid Pulse message: {err}"),
+        }
+
+        delivery
+            .ack(BasicAckOptions::default())
+            .await
+            .context("acknowledging message")?;
+    }
+
+    Ok(())
+}
+
+/// Parse an hgpush message into the push it announces, if any.
+///
+/// If a message announces several pushes, only the last one is returned, since
+/// a check of its head covers the others.
+fn parse_message(data: &[u8]) -> Result<Option<PulsePush>, serde_json::Error> {
+    let message: PulseMessage = serde_json::from_slice(data)?;
+    if message.payload.kind != CHANGEGROUP_MESSAGE_TYPE {
+        return Ok(None);
+    }
+
+    let data: ChangegroupData = serde_json::from_value(message.payload.data)?;
+
+    Ok(match (data.pushlog_pushes.last(), data.heads.last()) {
+        (Some(push), Some(head)) => Some(PulsePush {
+            push_id: push.pushid,
+            rev: head.clone(),
+        }),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(kind: &str, data: serde_json::Value) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "payload": {
+                "type": kind,
+                "data": data,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_changegroup_message() {
+        let data = message(
+            "changegroup.1",
+            json!({
+                "repo_url": "https://hg.mozilla.org/mozilla-central",
+                "heads": ["0123456789ab"],
+                "pushlog_pushes": [
+                    { "pushid": 41, "user": "someone@example.com" },
+                    { "pushid": 42, "user": "someone@example.com" },
+                ],
+            }),
+        );
+
+        let push = parse_message(&data).unwrap().unwrap();
+        assert_eq!(push.push_id, 42);
+        assert_eq!(push.rev, "0123456789ab");
+    }
+
+    #[test]
+    fn ignore_other_messages() {
+        let data = message(
+            "newrepo.1",
+            json!({ "repo_url": "https://hg.mozilla.org/x" }),
+        );
+        assert!(parse_message(&data).unwrap().is_none());
+
+        let data = message(
+            "changegroup.1",
+            json!({ "heads": [], "pushlog_pushes": [] }),
+        );
+        assert!(parse_message(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn reject_invalid_messages() {
+        assert!(parse_message(b"not JSON").is_err());
+
+        let data = message("changegroup.1", json!({ "heads": ["0123456789ab"] }));
+        assert!(parse_message(&data).is_err());
+    }
+}
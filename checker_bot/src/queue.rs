@@ -0,0 +1,137 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::io::Error;
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::storage::{CheckRequest, ManualCheck, SharedStore, Store};
+
+/// The maximum number of check requests waiting to be picked up by the worker.
+///
+/// Checks of pushes announced in the room always compare repository tips, so
+/// when the queue is full, dropping new requests doesn't prevent the pushes
+/// they're for from being checked. Pushes announced on Pulse are checked at
+/// their own revision, so their messages are handed back to Pulse instead.
+const CHECK_QUEUE_CAPACITY: usize = 64;
+
+/// The queue of checks waiting to be run by the check worker.
+///
+/// Requests are persisted in the store before being handed over to the worker,
+/// so they're not lost if the bot stops before running them. The worker
+/// removes them from the store once it has run them.
+#[derive(Clone)]
+pub(crate) struct CheckQueue {
+    requests: mpsc::Sender<CheckRequest>,
+    store: SharedStore,
+}
+
+/// The worker's end of a [`CheckQueue`].
+pub(crate) struct CheckRequests {
+    /// The requests left in the store's queue by the previous run of the bot,
+    /// oldest first.
+    pub resumed: Vec<CheckRequest>,
+
+    /// The receiver for the requests queued since the queue was created.
+    pub receiver: mpsc::Receiver<CheckRequest>,
+}
+
+impl CheckQueue {
+    /// Create a new queue, and return it along with the worker's end of it.
+    ///
+    /// The requests already in the store's queue are taken at this point, so
+    /// this must be called before anything can queue requests: otherwise the
+    /// worker would get the same requests both from the store and from the
+    /// receiver.
+    pub async fn new(store: SharedStore) -> (CheckQueue, CheckRequests) {
+        let resumed = store.lock().await.state().check_queue.clone();
+        let (requests, receiver) = mpsc::channel(CHECK_QUEUE_CAPACITY);
+
+        (
+            CheckQueue { requests, store },
+            CheckRequests { resumed, receiver },
+        )
+    }
+
+    /// Queue a check request, and return whether it is in the queue, i.e. it
+    /// was queued, or the same request already was.
+    ///
+    /// If the queue is full, or the worker has stopped, the request is dropped
+    /// and `false` is returned.
+    pub async fn enqueue(&self, request: CheckRequest) -> Result<bool, Error> {
+        let mut store = self.store.lock().await;
+        self.enqueue_locked(&mut store, request).await
+    }
+
+    /// Queue a check of the given revisions, and return its ID, or `None` if
+    /// the queue is full.
+    pub async fn enqueue_manual(
+        &self,
+        moz_rev: String,
+        tb_rev: Option<String>,
+    ) -> Result<Option<u64>, Error> {
+        let mut store = self.store.lock().await;
+
+        let id = store.next_check_id();
+        let request = CheckRequest::Manual(ManualCheck {
+            id,
+            moz_rev,
+            tb_rev,
+        });
+
+        match self.enqueue_locked(&mut store, request).await? {
+            true => Ok(Some(id)),
+            false => Ok(None),
+        }
+    }
+
+    /// Queue a check request with the store locked, and return whether it is
+    /// in the queue (see [`CheckQueue::enqueue`]).
+    ///
+    /// Keeping the store locked until the request is handed over makes sure
+    /// requests are persisted in the same order the worker receives them in.
+    async fn enqueue_locked(
+        &self,
+        store: &mut Store,
+        request: CheckRequest,
+    ) -> Result<bool, Error> {
+        // The same push can be seen twice if the bot stopped after queueing it
+        // but before storing the sync token.
+        if store
+            .state()
+            .check_queue
+            .iter()
+            .any(|queued| queued.is_duplicate_of(&request))
+        {
+            return Ok(true);
+        }
+
+        let permit = match self.requests.try_reserve() {
+            Ok(permit) => permit,
+            Err(TrySendError::Full(())) => {
+                log::warn!(
+                    "Dropping {} since the check queue is full",
+                    request.describe()
+                );
+                return Ok(false);
+            }
+            Err(TrySendError::Closed(())) => {
+                log::warn!(
+                    "Dropping {} since the check worker has stopped",
+                    request.describe()
+                );
+                return Ok(false);
+            }
+        };
+
+        store
+            .update(|state| state.check_queue.push(request.clone()))
+            .await?;
+
+        permit.send(request);
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,110 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::future;
+use std::io::Error;
+
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tokio::sync::watch;
+
+use crate::config::{Config, load_config_from_file};
+
+/// A handle used to find out whether the bot has been asked to shut down.
+#[derive(Debug, Clone)]
+pub(crate) struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    /// Whether a shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until a shutdown is requested.
+    ///
+    /// This returns immediately if a shutdown has already been requested, and
+    /// never returns if the signal handler has gone away without requesting
+    /// one.
+    pub async fn requested(&mut self) {
+        if self.is_requested() {
+            return;
+        }
+
+        if self.0.wait_for(|requested| *requested).await.is_err() {
+            future::pending::<()>().await
+        }
+    }
+}
+
+/// Handles the signals sent to the bot's process.
+///
+/// SIGTERM and SIGINT request a shutdown, and SIGHUP reloads the configuration
+/// file.
+pub(crate) struct SignalHandler {
+    sigterm: Signal,
+    sigint: Signal,
+    sighup: Signal,
+    config_file: String,
+    config: watch::Sender<Config>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl SignalHandler {
+    /// Start listening for signals.
+    ///
+    /// Returns the handler itself, which must then be run with
+    /// [`SignalHandler::run`], along with a receiver for the current
+    /// configuration (updated each time it is reloaded), and the signal to
+    /// listen to for shutdown requests.
+    pub fn install(
+        config_file: String,
+        cfg: Config,
+    ) -> Result<(SignalHandler, watch::Receiver<Config>, ShutdownSignal), Error> {
+        let (config, config_rx) = watch::channel(cfg);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        let handler = SignalHandler {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+            sighup: signal(SignalKind::hangup())?,
+            config_file,
+            config,
+            shutdown,
+        };
+
+        Ok((handler, config_rx, ShutdownSignal(shutdown_rx)))
+    }
+
+    /// Process signals as they come in.
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                _ = self.sigterm.recv() => self.request_shutdown("SIGTERM"),
+                _ = self.sigint.recv() => self.request_shutdown("SIGINT"),
+                _ = self.sighup.recv() => self.reload_config(),
+            }
+        }
+    }
+
+    fn request_shutdown(&self, signal_name: &str) {
+        match self.shutdown.send_replace(true) {
+            false => log::info!("Received {signal_name}, shutting down"),
+            true => log::info!("Received {signal_name}, already shutting down"),
+        }
+    }
+
+    /// Read and parse the configuration file again. If this fails, the current
+    /// configuration is kept.
+    fn reload_config(&self) {
+        log::info!("Received SIGHUP, reloading configuration");
+
+        match load_config_from_file(self.config_file.clone()) {
+            Ok(cfg) => {
+                self.config.send_replace(cfg);
+            }
+            Err(err) => {
+                log::error!("Error reloading config, keeping the current one: {err:?}")
+            }
+        }
+    }
+}
@@ -3,20 +3,633 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::env;
-use std::io::Error;
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use tokio::fs;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use checker_base::checksums::FileCheckResult;
+use checker_base::revision::ResolvedRev;
 
 use crate::config::Config;
 
-const SYNC_TOKEN_FILE_NAME: &str = "matrix_sync_token.txt";
+/// The name of the file holding the bot's state.
+const STATE_FILE_NAME: &str = "state.json";
+
+/// The name of the file holding the Matrix sync token.
+///
+/// The token changes after every sync, so it is kept out of the state file to
+/// avoid rewriting the whole state (including the check history) each time.
+const SYNC_TOKEN_FILE_NAME: &str = "sync_token.txt";
+
+/// The name of the file the Matrix sync token used to be stored in, before the
+/// state file was introduced.
+const LEGACY_SYNC_TOKEN_FILE_NAME: &str = "matrix_sync_token.txt";
+
+/// The current version of the state file's schema.
+///
+/// This must be incremented whenever a change to [`State`] requires existing
+/// state files to be migrated, with the migration implemented in
+/// [`migrate_state`].
+const STATE_VERSION: u64 = 3;
+
+/// The name of the file written to check whether the store is writable.
+const WRITE_CHECK_FILE_NAME: &str = ".write_check";
+
+/// The maximum number of check results to keep in the state file.
+const MAX_CHECK_HISTORY: usize = 1000;
+
+/// The bot's persisted state.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// The version of the schema this state was written with.
+    #[serde(default)]
+    pub version: u64,
+
+    /// The Matrix sync token to use for the next sync.
+    ///
+    /// This is stored in its own file rather than in the state file, but is
+    /// still read from state files written before that.
+    #[serde(default, skip_serializing)]
+    pub sync_token: Option<String>,
+
+    /// The Matrix filter uploaded to the server for sync requests.
+    pub filter: Option<StoredFilter>,
+
+    /// The last push notice the bot processed.
+    pub last_push: Option<ProcessedPush>,
+
+    /// The ID of the last notice the bot sent to the Matrix room.
+    pub last_notice_event_id: Option<String>,
+
+    /// The results of past checks, oldest first.
+    #[serde(default)]
+    pub check_history: Vec<CheckRecord>,
+
+    /// The ongoing incident, if the comm-central dependencies are currently out
+    /// of sync with mozilla-central.
+    pub incident: Option<Incident>,
+
+    /// The checks waiting to be run by the check worker, oldest first. They
+    /// are kept here so the ones accepted before a crash or a restart are run
+    /// after it.
+    #[serde(default)]
+    pub check_queue: Vec<CheckRequest>,
+
+    /// When the last scheduled check was queued, as a UNIX timestamp in
+    /// seconds.
+    pub last_scheduled_check: Option<u64>,
+
+    /// The ID given to the last check, see [`CheckRecord::id`].
+    #[serde(default)]
+    pub last_check_id: u64,
+}
+
+/// A period during which the comm-central dependencies are out of sync with
+/// mozilla-central.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Incident {
+    /// When a check first found the dependencies out of sync, in seconds since
+    /// the UNIX epoch.
+    pub since: u64,
+
+    /// The ID of the notice reporting the dependencies out of sync, if any.
+    pub notice_event_id: Option<String>,
+
+    /// The SHA512 checksum of the comm-central checksums file the last
+    /// successful check during this incident compared against, used to tell
+    /// whether a comm-central push changed the checksums.
+    pub checksums_digest: Option<String>,
+
+    /// Whether a reminder about this incident was sent to the Matrix room.
+    #[serde(default)]
+    pub reminder_sent: bool,
+
+    /// Whether this incident was escalated to the escalation room.
+    #[serde(default)]
+    pub escalated: bool,
+}
+
+/// A sync filter uploaded to the Matrix server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct StoredFilter {
+    /// The ID the server assigned to the filter.
+    pub id: String,
+
+    /// The JSON definition of the filter, used to figure out whether it needs
+    /// to be uploaded again after a configuration change.
+    pub definition: String,
+}
+
+/// A push notice processed by the bot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ProcessedPush {
+    /// When the notice was processed, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+
+    /// The body of the notice.
+    pub message: String,
+}
+
+/// The result of a check performed by the bot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CheckRecord {
+    /// The ID of the check, which can be used to look it up over HTTP. This is
+    /// unknown for checks recorded before IDs were introduced.
+    #[serde(default)]
+    pub id: Option<u64>,
+
+    /// When the check finished, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+
+    /// What caused the check to be performed.
+    pub trigger: Trigger,
+
+    /// The mozilla-central revision the check was performed against.
+    pub moz_rev: String,
+
+    /// The comm-central revision the check was performed against.
+    pub tb_rev: String,
+
+    /// The changeset the mozilla-central revision resolved to. This is `None`
+    /// if an error prevented the check from completing, or for checks recorded
+    /// before revisions were resolved.
+    #[serde(default)]
+    pub moz_changeset: Option<ResolvedRev>,
+
+    /// The changeset the comm-central revision resolved to, like
+    /// `moz_changeset`.
+    #[serde(default)]
+    pub tb_changeset: Option<ResolvedRev>,
+
+    /// The result for each mozilla-central file. This is empty if an error
+    /// prevented the check from completing.
+    pub files: Vec<FileCheckResult>,
+
+    /// How long the check took, in milliseconds.
+    pub duration_ms: u64,
+
+    /// The outcome of the check.
+    pub outcome: CheckOutcome,
+
+    /// The error that prevented the check from completing, if any.
+    pub error: Option<String>,
+
+    /// The ID of the notice sent to the Matrix room about this check, if any.
+    pub notice_event_id: Option<String>,
+}
+
+/// What caused a check to be performed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Trigger {
+    /// A push notice was sent to the Matrix room.
+    PushNotice {
+        /// The ID of the notice's event. This is unknown for checks recorded
+        /// before version 2 of the state schema.
+        event_id: Option<String>,
+    },
+
+    /// A comm-central push notice was sent to the Matrix room while the
+    /// dependencies were out of sync, and the push changed the checksums
+    /// stored in comm-central.
+    CommPushNotice {
+        /// The ID of the notice's event.
+        event_id: String,
+    },
+
+    /// The check was due according to the schedule in the configuration.
+    Schedule,
+
+    /// The check was requested over HTTP.
+    HttpRequest,
+
+    /// A mozilla-central push was announced on Pulse.
+    PulsePush {
+        /// The ID of the push in the repository's pushlog.
+        push_id: u64,
+    },
+}
+
+impl Trigger {
+    /// Whether checks with this trigger reflect the current sync status of the
+    /// dependencies, i.e. they compare the tips of the repositories at the
+    /// time. Checks requested over HTTP can be for any revision, so they don't
+    /// start or end incidents.
+    pub fn tracks_sync_status(&self) -> bool {
+        !matches!(self, Trigger::HttpRequest)
+    }
+}
+
+/// A request for the check worker to check the dependencies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum CheckRequest {
+    /// A mozilla-central push notice was sent to the Matrix room.
+    Push(QueuedPush),
+
+    /// A comm-central push notice was sent to the Matrix room.
+    CommPush(QueuedPush),
+
+    /// A check is due according to the schedule in the configuration.
+    Scheduled,
+
+    /// A check of specific revisions was requested over HTTP.
+    Manual(ManualCheck),
+
+    /// A mozilla-central push was announced on Pulse.
+    PulsePush(PulsePush),
+}
+
+impl CheckRequest {
+    /// The push notice that led to this request, if any.
+    pub fn push(&self) -> Option<&QueuedPush> {
+        match self {
+            CheckRequest::Push(push) | CheckRequest::CommPush(push) => Some(push),
+            CheckRequest::Scheduled | CheckRequest::Manual(_) | CheckRequest::PulsePush(_) => None,
+        }
+    }
+
+    /// Whether this request and the given one are for the same check, i.e.
+    /// they're for the same push notice or Pulse message, they're both
+    /// scheduled checks, or they're the same manual check.
+    pub fn is_duplicate_of(&self, other: &CheckRequest) -> bool {
+        match (self, other) {
+            (CheckRequest::Scheduled, CheckRequest::Scheduled) => true,
+            (CheckRequest::Manual(check), CheckRequest::Manual(other_check)) => {
+                check.id == other_check.id
+            }
+            (CheckRequest::PulsePush(push), CheckRequest::PulsePush(other_push)) => {
+                push.push_id == other_push.push_id
+            }
+            _ => match (self.push(), other.push()) {
+                (Some(push), Some(other_push)) => push.event_id == other_push.event_id,
+                _ => false,
+            },
+        }
+    }
+
+    /// A short description of the request, used in logs.
+    pub fn describe(&self) -> String {
+        match self {
+            CheckRequest::Push(push) | CheckRequest::CommPush(push) => {
+                format!("push {}", push.event_id)
+            }
+            CheckRequest::Scheduled => "scheduled check".to_string(),
+            CheckRequest::Manual(check) => format!("check {}", check.id),
+            CheckRequest::PulsePush(push) => format!("Pulse push {}", push.push_id),
+        }
+    }
+}
+
+/// A check of specific revisions, requested over HTTP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ManualCheck {
+    /// The ID the check will be recorded with.
+    pub id: u64,
+
+    /// The mozilla-central revision to check.
+    pub moz_rev: String,
+
+    /// The comm-central revision to check against. Defaults to the tip.
+    pub tb_rev: Option<String>,
+}
+
+/// A mozilla-central push announced on Pulse.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PulsePush {
+    /// The ID of the push in the repository's pushlog.
+    pub push_id: u64,
+
+    /// The revision at the head of the push.
+    pub rev: String,
+}
+
+/// A push notice waiting to be processed by the check worker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QueuedPush {
+    /// The ID of the notice's event.
+    pub event_id: String,
+
+    /// The body of the notice.
+    pub body: String,
+}
+
+/// The outcome of a check.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CheckOutcome {
+    /// The comm-central dependencies are in sync with mozilla-central.
+    UpToDate,
+
+    /// The comm-central dependencies need to be revendored.
+    OutOfDate,
+
+    /// An error occurred while performing the check.
+    Error,
+}
+
+/// A store shared between the tasks of the bot.
+pub(crate) type SharedStore = Arc<Mutex<Store>>;
+
+/// The bot's on-disk store.
+///
+/// The whole state is kept in memory, and written to a JSON file in the store
+/// directory every time it changes, except for the sync token, which is written
+/// to its own file.
+pub(crate) struct Store {
+    path: PathBuf,
+    sync_token_path: PathBuf,
+    state: State,
+}
+
+impl Store {
+    /// Open the store in the directory specified in the configuration.
+    ///
+    /// If there is no state file yet, a new state is created, including the
+    /// sync token from the legacy token file if there is one.
+    pub async fn open(cfg: &Config) -> Result<Store, Error> {
+        let path = get_path_in_store(cfg, STATE_FILE_NAME)?;
+        let sync_token_path = get_path_in_store(cfg, SYNC_TOKEN_FILE_NAME)?;
+        let state = Store::load_state(cfg).await?;
+
+        let store = Store {
+            path,
+            sync_token_path,
+            state,
+        };
+
+        // Write the state straight away, so a migrated state is persisted and
+        // an unwritable store directory is noticed on startup rather than
+        // after the first sync. The sync token is written as well, in case it
+        // came from the state file or the legacy token file.
+        store.persist_sync_token().await?;
+        store.persist().await?;
+
+        // Now that the legacy sync token is safely stored in the token file, we
+        // don't need the legacy file anymore.
+        let legacy_path = get_path_in_store(cfg, LEGACY_SYNC_TOKEN_FILE_NAME)?;
+        match fs::remove_file(&legacy_path).await {
+            Ok(_) => log::info!("Migrated legacy sync token file into the new token file"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        log::debug!("Opened store at path {}", store.path.to_string_lossy());
+
+        Ok(store)
+    }
+
+    /// Read the state from the store directory specified in the configuration,
+    /// migrating it in memory if needed, without writing anything to the
+    /// store. This is meant for looking at the state while the bot might be
+    /// running, see [`Store::open`] to make changes to it.
+    pub async fn load_state(cfg: &Config) -> Result<State, Error> {
+        let path = get_path_in_store(cfg, STATE_FILE_NAME)?;
+
+        let mut state = match fs::read_to_string(&path).await {
+            Ok(content) => migrate_state(serde_json::from_str(&content)?)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => State {
+                version: STATE_VERSION,
+                sync_token: read_sync_token(cfg, LEGACY_SYNC_TOKEN_FILE_NAME).await?,
+                ..Default::default()
+            },
+            Err(err) => return Err(err),
+        };
+
+        // The token file is more recent than a token from the state file.
+        if let Some(token) = read_sync_token(cfg, SYNC_TOKEN_FILE_NAME).await? {
+            state.sync_token = Some(token);
+        }
+
+        Ok(state)
+    }
+
+    /// The current state.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Apply the given change to the state, and persist the new state.
+    pub async fn update<F>(&mut self, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut State),
+    {
+        change(&mut self.state);
+        self.persist().await
+    }
+
+    /// Update the Matrix sync token, so we don't need to perform a full sync
+    /// after the next restart. Only the token file is written.
+    pub async fn store_sync_token(&mut self, token: &str) -> Result<(), Error> {
+        log::debug!("Storing token {token}");
+        self.state.sync_token = Some(token.to_string());
+        self.persist_sync_token().await
+    }
+
+    /// Record the result of a check, dropping the oldest result if the history
+    /// is full. The check is given a new ID if it doesn't have one yet.
+    ///
+    /// If the check found the dependencies out of sync, an incident is started
+    /// (unless there's one already). If it found them in sync, the ongoing
+    /// incident (if any) is ended. Checks that don't track the sync status
+    /// (see [`Trigger::tracks_sync_status`]) are only recorded.
+    pub async fn record_check(&mut self, mut record: CheckRecord) -> Result<(), Error> {
+        if record.id.is_none() {
+            record.id = Some(self.next_check_id());
+        }
+
+        self.update(|state| {
+            if record.notice_event_id.is_some() {
+                state.last_notice_event_id = record.notice_event_id.clone();
+            }
+
+            update_incident(&mut state.incident, &record);
+
+            state.check_history.push(record);
+            if state.check_history.len() > MAX_CHECK_HISTORY {
+                state.check_history.remove(0);
+            }
+        })
+        .await
+    }
+
+    /// Give out a new check ID. The new ID is persisted the next time the state
+    /// is.
+    pub fn next_check_id(&mut self) -> u64 {
+        self.state.last_check_id += 1;
+        self.state.last_check_id
+    }
+
+    /// Write the state (except the sync token) to disk.
+    async fn persist(&self) -> Result<(), Error> {
+        let content = serde_json::to_vec_pretty(&self.state)?;
+        write_atomically(&self.path, &content).await
+    }
+
+    /// Write the sync token to disk, if there is one.
+    async fn persist_sync_token(&self) -> Result<(), Error> {
+        match &self.state.sync_token {
+            Some(token) => write_atomically(&self.sync_token_path, token.as_bytes()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write the given content to the file at the given path.
+///
+/// The content is first written to a temporary file which is then renamed over
+/// the file, so a crash in the middle of a write never leaves a truncated file
+/// behind.
+async fn write_atomically(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut file = File::create(&tmp_path).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&tmp_path, path).await
+}
+
+/// Bring a state read from disk up to date with the current schema.
+fn migrate_state(mut value: Value) -> Result<State, Error> {
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+
+    if version > STATE_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "state file has version {version}, newer than supported version {STATE_VERSION}"
+            ),
+        ));
+    }
+
+    // Each migration transforms `value` from one version of the schema to the
+    // next one.
+    if version < 2 {
+        migrate_state_to_v2(&mut value);
+    }
+    if version < 3 {
+        migrate_state_to_v3(&mut value)?;
+    }
+
+    let mut state: State = serde_json::from_value(value)?;
+    state.version = STATE_VERSION;
+
+    Ok(state)
+}
+
+/// Version 2 of the schema added details to each check record. Records from
+/// version 1 were only ever created for push notices, and always compared the
+/// tips of both repositories.
+fn migrate_state_to_v2(value: &mut Value) {
+    let Some(records) = value.get_mut("check_history").and_then(Value::as_array_mut) else {
+        return;
+    };
+
+    for record in records.iter_mut().filter_map(Value::as_object_mut) {
+        record.insert(
+            "trigger".into(),
+            json!({ "type": "push_notice", "event_id": null }),
+        );
+        record.insert("moz_rev".into(), json!("tip"));
+        record.insert("tb_rev".into(), json!("tip"));
+        record.insert("files".into(), json!([]));
+        record.insert("duration_ms".into(), json!(0));
+        record.insert("error".into(), Value::Null);
+    }
+}
+
+/// Version 3 of the schema added the ongoing incident, which we can figure out
+/// from the check history.
+fn migrate_state_to_v3(value: &mut Value) -> Result<(), Error> {
+    let Some(state) = value.as_object_mut() else {
+        return Ok(());
+    };
+
+    let records: Vec<CheckRecord> = match state.get("check_history") {
+        Some(records) => serde_json::from_value(records.clone())?,
+        None => vec![],
+    };
+
+    let mut incident = None;
+    for record in &records {
+        update_incident(&mut incident, record);
+    }
+
+    state.insert("incident".into(), serde_json::to_value(incident)?);
+
+    Ok(())
+}
+
+/// Start or end an incident according to the outcome of the given check.
+fn update_incident(incident: &mut Option<Incident>, record: &CheckRecord) {
+    if !record.trigger.tracks_sync_status() {
+        return;
+    }
+
+    match record.outcome {
+        CheckOutcome::OutOfDate if incident.is_none() => {
+            *incident = Some(Incident {
+                since: record.timestamp,
+                notice_event_id: record.notice_event_id.clone(),
+                checksums_digest: None,
+                reminder_sent: false,
+                escalated: false,
+            })
+        }
+        CheckOutcome::UpToDate => *incident = None,
+        _ => {}
+    }
+}
+
+/// Read the sync token from the given file in the store directory, if it
+/// exists.
+async fn read_sync_token(cfg: &Config, file: &str) -> Result<Option<String>, Error> {
+    let path = get_path_in_store(cfg, file)?;
+
+    match fs::read_to_string(&path).await {
+        Ok(token) if !token.is_empty() => {
+            log::debug!("Read token {} at path {}", token, path.to_string_lossy());
+            Ok(Some(token))
+        }
+        Ok(_) => Ok(None),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Check that files can be created in the store directory.
+pub(crate) async fn check_store_writable(cfg: &Config) -> Result<(), Error> {
+    let path = get_path_in_store(cfg, WRITE_CHECK_FILE_NAME)?;
+    fs::write(&path, b"").await?;
+    fs::remove_file(&path).await?;
+    Ok(())
+}
+
+/// The current time in seconds since the UNIX epoch.
+pub(crate) fn now_ts() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        // We know the current time is after the UNIX epoch, so unwrapping
+        // should not panic.
+        .unwrap()
+        .as_secs()
+}
 
 /// Build a path to the given store file using the configuration.
 ///
 /// If the configuration does not specify a directory, the current working
 /// directory is used instead.
-fn get_path_in_store(cfg: &Config, file: &str) -> Result<PathBuf, Error> {
+pub(crate) fn get_path_in_store(cfg: &Config, file: &str) -> Result<PathBuf, Error> {
     let mut path = match &cfg.store_location {
         Some(path) => PathBuf::from(path),
         None => env::current_dir()?,
@@ -27,19 +640,144 @@ fn get_path_in_store(cfg: &Config, file: &str) -> Result<PathBuf, Error> {
     Ok(path)
 }
 
-/// Update the Matrix sync token in the on-disk store, so we don't need to
-/// perform a full sync after the next restart.
-pub(crate) async fn store_sync_token(cfg: &Config, token: &String) -> Result<(), Error> {
-    let path = get_path_in_store(cfg, SYNC_TOKEN_FILE_NAME)?;
-    log::debug!("Storing token {} at path {}", token, path.to_string_lossy());
-    fs::write(path, token).await?;
-    Ok(())
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state written by the first version of the bot with a state file.
+    fn v1_state(outcomes: &[&str]) -> Value {
+        let records = outcomes
+            .iter()
+            .enumerate()
+            .map(|(idx, outcome)| {
+                json!({
+                    "timestamp": 100 * (idx as u64 + 1),
+                    "outcome": outcome,
+                    "notice_event_id": format!("$notice{idx}"),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "version": 1,
+            "sync_token": "s1_2_3",
+            "filter": null,
+            "last_push": null,
+            "last_notice_event_id": "$notice0",
+            "check_history": records,
+        })
+    }
+
+    #[test]
+    fn migrate_v1_adds_record_details() {
+        let state = migrate_state(v1_state(&["up_to_date", "error"])).unwrap();
+
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.sync_token.as_deref(), Some("s1_2_3"));
+        assert_eq!(state.check_history.len(), 2);
+
+        let record = &state.check_history[1];
+        assert_eq!(record.timestamp, 200);
+        assert_eq!(record.outcome, CheckOutcome::Error);
+        assert_eq!(record.notice_event_id.as_deref(), Some("$notice1"));
+        assert!(matches!(
+            record.trigger,
+            Trigger::PushNotice { event_id: None }
+        ));
+        assert_eq!(record.moz_rev, "tip");
+        assert_eq!(record.tb_rev, "tip");
+        assert!(record.files.is_empty());
+        assert_eq!(record.error, None);
+    }
+
+    #[test]
+    fn migrate_v1_starts_incident_from_history() {
+        let state = migrate_state(v1_state(&[
+            "up_to_date",
+            "out_of_date",
+            "error",
+            "out_of_date",
+        ]))
+        .unwrap();
+
+        // The incident started with the first check that found the
+        // dependencies out of date since they were last up to date.
+        let incident = state.incident.unwrap();
+        assert_eq!(incident.since, 200);
+        assert_eq!(incident.notice_event_id.as_deref(), Some("$notice1"));
+        assert_eq!(incident.checksums_digest, None);
+    }
+
+    #[test]
+    fn migrate_v1_without_incident() {
+        let state = migrate_state(v1_state(&["out_of_date", "up_to_date"])).unwrap();
+        assert!(state.incident.is_none());
+
+        let state = migrate_state(v1_state(&[])).unwrap();
+        assert!(state.incident.is_none());
+        assert!(state.check_history.is_empty());
+    }
+
+    #[test]
+    fn migrate_state_without_version() {
+        let state = migrate_state(json!({ "sync_token": "s1_2_3" })).unwrap();
+
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.sync_token.as_deref(), Some("s1_2_3"));
+        assert!(state.check_history.is_empty());
+    }
+
+    #[test]
+    fn migrate_rejects_newer_version() {
+        let err = migrate_state(json!({ "version": STATE_VERSION + 1 })).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    fn push(event_id: &str) -> QueuedPush {
+        QueuedPush {
+            event_id: event_id.to_string(),
+            body: "Push to mozilla-central".to_string(),
+        }
+    }
+
+    #[test]
+    fn requests_for_the_same_push_are_duplicates() {
+        let request = CheckRequest::Push(push("$a"));
+
+        assert!(request.is_duplicate_of(&CheckRequest::Push(push("$a"))));
+        assert!(!request.is_duplicate_of(&CheckRequest::Push(push("$b"))));
+
+        // A notice is either a mozilla-central or a comm-central push, so the
+        // event ID is enough to tell them apart.
+        assert!(request.is_duplicate_of(&CheckRequest::CommPush(push("$a"))));
+    }
+
+    #[test]
+    fn scheduled_requests_are_duplicates() {
+        assert!(CheckRequest::Scheduled.is_duplicate_of(&CheckRequest::Scheduled));
+        assert!(!CheckRequest::Scheduled.is_duplicate_of(&CheckRequest::Push(push("$a"))));
+    }
+
+    #[test]
+    fn manual_and_pulse_requests_are_compared_by_id() {
+        let manual = |id| {
+            CheckRequest::Manual(ManualCheck {
+                id,
+                moz_rev: "0123456789ab".to_string(),
+                tb_rev: None,
+            })
+        };
+        assert!(manual(1).is_duplicate_of(&manual(1)));
+        assert!(!manual(1).is_duplicate_of(&manual(2)));
 
-/// Read the Matrix sync token to use for the first sync at startup.
-pub(crate) async fn read_sync_token_from_store(cfg: &Config) -> Result<String, Error> {
-    let path = get_path_in_store(cfg, SYNC_TOKEN_FILE_NAME)?;
-    let token = fs::read_to_string(path.clone()).await?;
-    log::debug!("Read token {} at path {}", token, path.to_string_lossy());
-    Ok(token)
+        let pulse = |push_id| {
+            CheckRequest::PulsePush(PulsePush {
+                push_id,
+                rev: "0123456789ab".to_string(),
+            })
+        };
+        assert!(pulse(1).is_duplicate_of(&pulse(1)));
+        assert!(!pulse(1).is_duplicate_of(&pulse(2)));
+        assert!(!pulse(1).is_duplicate_of(&manual(1)));
+    }
 }
@@ -0,0 +1,469 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::time::Duration;
+
+use anyhow::{Error, bail};
+use serde::Deserialize;
+
+use crate::history::format_duration;
+use crate::storage::CheckOutcome;
+
+/// A value that can be inserted into a message template, written as its name
+/// between braces, e.g. `{moz_rev}`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placeholder {
+    /// The mozilla-central revision that was checked.
+    MozRev,
+
+    /// The comm-central revision that was checked.
+    CommRev,
+
+    /// The mozilla-central files whose checksums don't match.
+    MismatchedFiles,
+
+    /// The crates locked to different versions in mozilla-central and
+    /// comm-central.
+    ChangedCrates,
+
+    /// The ID of the push that triggered the check, i.e. the most recent one
+    /// if the check covers several pushes.
+    PushId,
+
+    /// The IDs of all the pushes the check covers.
+    PushIds,
+
+    /// The error that prevented the check from completing.
+    Error,
+
+    /// How long the check took.
+    Duration,
+
+    /// How long the dependencies have been out of sync for.
+    OutOfSyncFor,
+
+    /// The ID the check is recorded with.
+    CheckId,
+
+    /// The outcome of the check, e.g. `out of date`.
+    Outcome,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Placeholder> {
+        let placeholder = match name {
+            "moz_rev" => Placeholder::MozRev,
+            "comm_rev" => Placeholder::CommRev,
+            "mismatched_files" => Placeholder::MismatchedFiles,
+            "changed_crates" => Placeholder::ChangedCrates,
+            "push_id" => Placeholder::PushId,
+            "push_ids" => Placeholder::PushIds,
+            "error" => Placeholder::Error,
+            "duration" => Placeholder::Duration,
+            "out_of_sync_for" => Placeholder::OutOfSyncFor,
+            "check_id" => Placeholder::CheckId,
+            "outcome" => Placeholder::Outcome,
+            _ => return None,
+        };
+
+        Some(placeholder)
+    }
+}
+
+/// A part of a parsed template.
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A parsed message template.
+///
+/// Placeholders are written as their name between braces (e.g. `{moz_rev}`).
+/// Literal braces are written by doubling them (`{{` and `}}`).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parse the given template, failing if it includes an unknown placeholder
+    /// or an unmatched brace.
+    pub fn parse(template: &str) -> Result<Template, Error> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("unclosed placeholder {{{name}"),
+                        }
+                    }
+
+                    let Some(placeholder) = Placeholder::from_name(&name) else {
+                        bail!("unknown placeholder {{{name}}}");
+                    };
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder));
+                }
+                '}' => bail!("unmatched '}}' (use '}}}}' for a literal brace)"),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template { segments })
+    }
+
+    /// Render the template with the given details, escaping the values of
+    /// placeholders with the given function.
+    fn render_with<F>(&self, details: &CheckDetails, escape: F) -> String
+    where
+        F: Fn(&str) -> String,
+    {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Placeholder(placeholder) => escape(&details.value(*placeholder)),
+            })
+            .collect()
+    }
+}
+
+/// A message the bot sends, with an optional HTML variant.
+///
+/// In the configuration file, a message is either a string (which is then used
+/// as the plain text template), or a table with `plain` and `html` keys.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawMessageTemplate")]
+pub(crate) struct MessageTemplate {
+    plain: Template,
+    html: Option<Template>,
+}
+
+/// A message template, as written in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum RawMessageTemplate {
+    Plain(String),
+    Variants { plain: String, html: Option<String> },
+}
+
+impl TryFrom<RawMessageTemplate> for MessageTemplate {
+    type Error = Error;
+
+    fn try_from(raw: RawMessageTemplate) -> Result<Self, Self::Error> {
+        let (plain, html) = match raw {
+            RawMessageTemplate::Plain(plain) => (plain, None),
+            RawMessageTemplate::Variants { plain, html } => (plain, html),
+        };
+
+        Ok(MessageTemplate {
+            plain: Template::parse(&plain)?,
+            html: html.as_deref().map(Template::parse).transpose()?,
+        })
+    }
+}
+
+impl MessageTemplate {
+    /// Parse a message with no HTML variant.
+    pub fn plain(template: &str) -> Result<MessageTemplate, Error> {
+        MessageTemplate::try_from(RawMessageTemplate::Plain(template.to_string()))
+    }
+
+    /// Render the message with the given details.
+    pub fn render(&self, details: &CheckDetails) -> RenderedMessage {
+        RenderedMessage {
+            body: self.plain.render_with(details, str::to_string),
+            html_body: self
+                .html
+                .as_ref()
+                .map(|html| html.render_with(details, escape_html)),
+            mentions: vec![],
+        }
+    }
+}
+
+/// A message rendered from a [`MessageTemplate`].
+#[derive(Debug, Clone)]
+pub(crate) struct RenderedMessage {
+    /// The plain text body of the message.
+    pub body: String,
+
+    /// The HTML body of the message, if the template has an HTML variant.
+    pub html_body: Option<String>,
+
+    /// The IDs of the Matrix users mentioned in the message.
+    pub mentions: Vec<String>,
+}
+
+impl RenderedMessage {
+    /// The HTML body of the message, generated from the plain text one if the
+    /// template has no HTML variant.
+    pub fn html(&self) -> String {
+        match &self.html_body {
+            Some(html_body) => html_body.clone(),
+            None => escape_html(&self.body).replace('\n', "<br>"),
+        }
+    }
+
+    /// Mention the given Matrix users at the start of the message.
+    ///
+    /// Clients only render mentions as pills in HTML messages, so an HTML
+    /// version of the message is generated from the plain text one if needed.
+    pub fn with_mentions(self, user_ids: &[String]) -> RenderedMessage {
+        if user_ids.is_empty() {
+            return self;
+        }
+
+        let pills = user_ids
+            .iter()
+            .map(|user_id| {
+                let user_id = escape_html(user_id);
+                format!("<a href=\"https://matrix.to/#/{user_id}\">{user_id}</a>")
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        RenderedMessage {
+            body: format!("{}: {}", user_ids.join(" "), self.body),
+            html_body: Some(format!("{pills}: {}", self.html())),
+            mentions: user_ids.to_vec(),
+        }
+    }
+}
+
+/// The details of a check, used to fill in the placeholders of a message
+/// template.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CheckDetails {
+    pub moz_rev: String,
+    pub comm_rev: String,
+    pub mismatched_files: Vec<String>,
+
+    /// `None` if the changed crates could not be figured out.
+    pub changed_crates: Option<Vec<String>>,
+
+    pub push_id: Option<String>,
+    pub push_ids: Vec<String>,
+    pub error: Option<String>,
+    pub duration: Duration,
+
+    /// `None` if the dependencies weren't out of sync before the check.
+    pub out_of_sync_for: Option<Duration>,
+
+    pub check_id: Option<u64>,
+    pub outcome: Option<CheckOutcome>,
+}
+
+impl CheckDetails {
+    /// The value to insert in place of the given placeholder.
+    fn value(&self, placeholder: Placeholder) -> String {
+        let list = |items: &[String]| match items.is_empty() {
+            true => "none".to_string(),
+            false => items.join(", "),
+        };
+
+        match placeholder {
+            Placeholder::MozRev => self.moz_rev.clone(),
+            Placeholder::CommRev => self.comm_rev.clone(),
+            Placeholder::MismatchedFiles => list(&self.mismatched_files),
+            Placeholder::ChangedCrates => match &self.changed_crates {
+                Some(crates) => list(crates),
+                None => "unknown".to_string(),
+            },
+            Placeholder::PushId => self.push_id.clone().unwrap_or("unknown".to_string()),
+            Placeholder::PushIds => match self.push_ids.is_empty() {
+                true => "unknown".to_string(),
+                false => self.push_ids.join(", "),
+            },
+            Placeholder::Error => self.error.clone().unwrap_or_default(),
+            Placeholder::Duration => format!("{:.1}s", self.duration.as_secs_f64()),
+            Placeholder::OutOfSyncFor => match self.out_of_sync_for {
+                Some(duration) => format_duration(duration.as_secs()),
+                None => "unknown".to_string(),
+            },
+            Placeholder::CheckId => match self.check_id {
+                Some(id) => id.to_string(),
+                None => "unknown".to_string(),
+            },
+            Placeholder::Outcome => match self.outcome {
+                Some(CheckOutcome::UpToDate) => "up to date".to_string(),
+                Some(CheckOutcome::OutOfDate) => "out of date".to_string(),
+                Some(CheckOutcome::Error) => "error".to_string(),
+                None => "unknown".to_string(),
+            },
+        }
+    }
+}
+
+/// Escape the characters that have a special meaning in HTML.
+pub(crate) fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details() -> CheckDetails {
+        CheckDetails {
+            moz_rev: "0123456789ab".to_string(),
+            comm_rev: "ba9876543210".to_string(),
+            mismatched_files: vec!["Cargo.lock".to_string(), "Cargo.toml".to_string()],
+            duration: Duration::from_millis(1500),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_splits_literals_and_placeholders() {
+        let template = Template::parse("Checked {moz_rev} against {comm_rev}").unwrap();
+
+        assert_eq!(
+            template.segments,
+            vec![
+                Segment::Literal("Checked ".to_string()),
+                Segment::Placeholder(Placeholder::MozRev),
+                Segment::Literal(" against ".to_string()),
+                Segment::Placeholder(Placeholder::CommRev),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unescapes_doubled_braces() {
+        let template = Template::parse("{{moz_rev}} is {moz_rev}").unwrap();
+
+        assert_eq!(
+            template.segments,
+            vec![
+                Segment::Literal("{moz_rev} is ".to_string()),
+                Segment::Placeholder(Placeholder::MozRev),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        let err = Template::parse("{unknown}").unwrap_err();
+        assert_eq!(err.to_string(), "unknown placeholder {unknown}");
+
+        let err = Template::parse("Checked {moz_rev").unwrap_err();
+        assert_eq!(err.to_string(), "unclosed placeholder {moz_rev");
+
+        assert!(Template::parse("Checked }").is_err());
+    }
+
+    #[test]
+    fn render_fills_in_details() {
+        let template = MessageTemplate::plain(
+            "{moz_rev}/{comm_rev}: {mismatched_files}, crates {changed_crates}, took {duration}",
+        )
+        .unwrap();
+
+        let message = template.render(&details());
+
+        assert_eq!(
+            message.body,
+            "0123456789ab/ba9876543210: Cargo.lock, Cargo.toml, crates unknown, took 1.5s"
+        );
+        assert_eq!(message.html_body, None);
+    }
+
+    #[test]
+    fn render_describes_missing_values() {
+        let template =
+            MessageTemplate::plain("{push_id} {push_ids} {out_of_sync_for} {check_id} {outcome}")
+                .unwrap();
+
+        let message = template.render(&CheckDetails {
+            changed_crates: Some(vec![]),
+            ..Default::default()
+        });
+
+        assert_eq!(message.body, "unknown unknown unknown unknown unknown");
+    }
+
+    #[test]
+    fn render_escapes_values_in_html() {
+        let template = MessageTemplate::try_from(RawMessageTemplate::Variants {
+            plain: "{error}".to_string(),
+            html: Some("<b>Error:</b> {error}".to_string()),
+        })
+        .unwrap();
+
+        let message = template.render(&CheckDetails {
+            error: Some("<unexpected> & \"odd\"".to_string()),
+            ..Default::default()
+        });
+
+        assert_eq!(message.body, "<unexpected> & \"odd\"");
+        assert_eq!(
+            message.html_body.as_deref(),
+            Some("<b>Error:</b> &lt;unexpected&gt; &amp; &quot;odd&quot;")
+        );
+    }
+
+    #[test]
+    fn html_is_generated_from_plain_body() {
+        let message = MessageTemplate::plain("<Line 1>\nLine 2")
+            .unwrap()
+            .render(&details());
+
+        assert_eq!(message.html(), "&lt;Line 1&gt;<br>Line 2");
+    }
+
+    #[test]
+    fn with_mentions_prefixes_the_message() {
+        let message = MessageTemplate::plain("Out of date")
+            .unwrap()
+            .render(&details())
+            .with_mentions(&["@alice:example.com".to_string()]);
+
+        assert_eq!(message.body, "@alice:example.com: Out of date");
+        assert_eq!(
+            message.html_body.as_deref(),
+            Some(
+                "<a href=\"https://matrix.to/#/@alice:example.com\">@alice:example.com</a>: \
+                 Out of date"
+            )
+        );
+        assert_eq!(message.mentions, vec!["@alice:example.com".to_string()]);
+    }
+}
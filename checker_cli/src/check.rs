@@ -0,0 +1,129 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde::Serialize;
+
+use checker_base::checksums::{ChangeSet, CheckReport, FileCheckResult, verify_checksums_match};
+use checker_base::error::Error;
+
+use crate::output::{ChangesetOutput, Format, Output, Status};
+
+/// The result of a check.
+#[derive(Debug, Serialize)]
+struct CheckOutput<'a> {
+    status: Status,
+    moz_rev: &'a str,
+    comm_rev: &'a str,
+    moz_changeset: Option<ChangesetOutput<'a>>,
+    comm_changeset: Option<ChangesetOutput<'a>>,
+    files: Vec<CheckOutputFile<'a>>,
+    error: Option<String>,
+}
+
+/// The result of checking a single file, in a [`CheckOutput`].
+#[derive(Debug, Serialize)]
+struct CheckOutputFile<'a> {
+    path: &'a str,
+    expected_checksum: &'a str,
+    actual_checksum: &'a str,
+    matches: bool,
+    fetch_duration_ms: u64,
+    size: u64,
+    content_type: Option<&'a str>,
+}
+
+impl<'a> From<&'a FileCheckResult> for CheckOutputFile<'a> {
+    fn from(file: &'a FileCheckResult) -> Self {
+        CheckOutputFile {
+            path: &file.path,
+            expected_checksum: &file.expected_checksum,
+            actual_checksum: &file.actual_checksum,
+            matches: file.matches(),
+            fetch_duration_ms: file.fetch_duration_ms,
+            size: file.size,
+            content_type: file.content_type.as_deref(),
+        }
+    }
+}
+
+impl<'a> CheckOutput<'a> {
+    fn new(change_set: &'a ChangeSet, result: &'a Result<CheckReport, Error>) -> CheckOutput<'a> {
+        match result {
+            Ok(report) => CheckOutput {
+                status: match report.checksums_match() {
+                    true => Status::InSync,
+                    false => Status::OutOfSync,
+                },
+                moz_rev: change_set.moz_rev(),
+                comm_rev: change_set.tb_rev(),
+                moz_changeset: Some(ChangesetOutput::from(&report.moz_rev)),
+                comm_changeset: Some(ChangesetOutput::from(&report.tb_rev)),
+                files: report.files.iter().map(CheckOutputFile::from).collect(),
+                error: None,
+            },
+            Err(err) => CheckOutput {
+                status: Status::Error,
+                moz_rev: change_set.moz_rev(),
+                comm_rev: change_set.tb_rev(),
+                moz_changeset: None,
+                comm_changeset: None,
+                files: vec![],
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+impl Output for CheckOutput<'_> {
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (repo, rev, changeset) in [
+            ("mozilla-central", self.moz_rev, &self.moz_changeset),
+            ("comm-central", self.comm_rev, &self.comm_changeset),
+        ] {
+            match changeset {
+                Some(changeset) => text.push_str(&format!(
+                    "{repo} revision: {rev} ({})\n",
+                    changeset.to_text()
+                )),
+                None => text.push_str(&format!("{repo} revision: {rev}\n")),
+            }
+        }
+
+        for file in &self.files {
+            match file.matches {
+                true => text.push_str(&format!("{}: matches\n", file.path)),
+                false => text.push_str(&format!(
+                    "{}: does not match (expected {}, got {})\n",
+                    file.path, file.expected_checksum, file.actual_checksum
+                )),
+            }
+        }
+
+        text.push_str(match self.status {
+            Status::InSync => "Checksums match",
+            Status::OutOfSync => "Checksums do not match",
+            Status::Error => "Error while verifying files: ",
+        });
+        if let Some(error) = &self.error {
+            text.push_str(error);
+        }
+
+        text
+    }
+}
+
+/// Compare the checksums of the mozilla-central files to the ones stored in
+/// comm-central, print the result, and return the exit code.
+pub(crate) async fn check(change_set: &ChangeSet, format: Format) -> u8 {
+    let result = verify_checksums_match(change_set.clone()).await;
+    if let Err(err) = &result {
+        log::error!("error while verifying files: {err}");
+    }
+
+    let output = CheckOutput::new(change_set, &result);
+    output.print(format);
+
+    output.status.exit_code()
+}
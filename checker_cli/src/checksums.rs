@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde::Serialize;
+
+use checker_base::checksums::{ChangeSet, FileChecksum, Repo, compute_checksums};
+use checker_base::error::Error;
+use checker_base::revision::{ResolvedRev, resolve_rev};
+
+use crate::output::{ChangesetOutput, EXIT_ERROR, EXIT_SUCCESS, Format, Output};
+
+/// The checksums of the mozilla-central files at a given revision.
+#[derive(Debug, Serialize)]
+struct ChecksumsOutput<'a> {
+    moz_rev: &'a str,
+    moz_changeset: Option<ChangesetOutput<'a>>,
+    files: &'a [FileChecksum],
+    error: Option<String>,
+}
+
+impl Output for ChecksumsOutput<'_> {
+    fn to_text(&self) -> String {
+        // Use the same format as `sha512sum`.
+        let mut lines = self
+            .files
+            .iter()
+            .map(|file| format!("{}  {}", file.checksum, file.path))
+            .collect::<Vec<_>>();
+
+        if let Some(error) = &self.error {
+            lines.push(format!("Error while computing checksums: {error}"));
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Resolve the mozilla-central revision to a changeset, so that all the files
+/// come from it, then compute their checksums.
+async fn resolve_and_compute(
+    change_set: &ChangeSet,
+) -> Result<(ResolvedRev, Vec<FileChecksum>), Error> {
+    let moz_rev = resolve_rev(change_set, Repo::Firefox).await?;
+    let pinned = ChangeSet {
+        moz_rev: Some(moz_rev.node.clone()),
+        ..change_set.clone()
+    };
+
+    Ok((moz_rev, compute_checksums(&pinned).await?))
+}
+
+/// Compute the checksums of the mozilla-central files, print them, and return
+/// the exit code.
+pub(crate) async fn checksums(change_set: &ChangeSet, format: Format) -> u8 {
+    let (moz_rev, files, error) = match resolve_and_compute(change_set).await {
+        Ok((moz_rev, files)) => (Some(moz_rev), files, None),
+        Err(err) => {
+            log::error!("error while computing checksums: {err}");
+            (None, vec![], Some(err.to_string()))
+        }
+    };
+
+    let output = ChecksumsOutput {
+        moz_rev: change_set.moz_rev(),
+        moz_changeset: moz_rev.as_ref().map(ChangesetOutput::from),
+        files: &files,
+        error,
+    };
+    output.print(format);
+
+    match output.error {
+        Some(_) => EXIT_ERROR,
+        None => EXIT_SUCCESS,
+    }
+}
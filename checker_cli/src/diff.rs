@@ -0,0 +1,97 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde::Serialize;
+
+use checker_base::checksums::ChangeSet;
+use checker_base::lockfile::{CrateChange, changed_crates};
+
+use crate::output::{Format, Output, Status};
+
+/// The crates locked to different versions in mozilla-central and
+/// comm-central.
+#[derive(Debug, Serialize)]
+struct DiffOutput<'a> {
+    status: Status,
+    moz_rev: &'a str,
+    comm_rev: &'a str,
+    crates: Vec<DiffOutputCrate<'a>>,
+    error: Option<String>,
+}
+
+/// A crate locked to different versions, in a [`DiffOutput`].
+#[derive(Debug, Serialize)]
+struct DiffOutputCrate<'a> {
+    name: &'a str,
+    comm_versions: &'a [String],
+    moz_versions: &'a [String],
+
+    #[serde(skip)]
+    change: &'a CrateChange,
+}
+
+impl<'a> From<&'a CrateChange> for DiffOutputCrate<'a> {
+    fn from(change: &'a CrateChange) -> Self {
+        DiffOutputCrate {
+            name: &change.name,
+            comm_versions: &change.tb_versions,
+            moz_versions: &change.moz_versions,
+            change,
+        }
+    }
+}
+
+impl Output for DiffOutput<'_> {
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "mozilla-central revision: {}\ncomm-central revision: {}\n",
+            self.moz_rev, self.comm_rev
+        );
+
+        for krate in &self.crates {
+            text.push_str(&format!("{}\n", krate.change));
+        }
+
+        match &self.error {
+            Some(error) => text.push_str(&format!("Error while comparing crates: {error}")),
+            None if self.crates.is_empty() => text.push_str("No crate differs"),
+            None => text.push_str(&format!("{} crate(s) differ", self.crates.len())),
+        }
+
+        text
+    }
+}
+
+/// List the crates locked to different versions in mozilla-central and
+/// comm-central, print them, and return the exit code.
+pub(crate) async fn diff(change_set: &ChangeSet, format: Format) -> u8 {
+    let result = changed_crates(change_set).await;
+
+    let output = match &result {
+        Ok(changes) => DiffOutput {
+            status: match changes.is_empty() {
+                true => Status::InSync,
+                false => Status::OutOfSync,
+            },
+            moz_rev: change_set.moz_rev(),
+            comm_rev: change_set.tb_rev(),
+            crates: changes.iter().map(DiffOutputCrate::from).collect(),
+            error: None,
+        },
+        Err(err) => {
+            log::error!("error while comparing crates: {err}");
+
+            DiffOutput {
+                status: Status::Error,
+                moz_rev: change_set.moz_rev(),
+                comm_rev: change_set.tb_rev(),
+                crates: vec![],
+                error: Some(err.to_string()),
+            }
+        }
+    };
+    output.print(format);
+
+    output.status.exit_code()
+}
@@ -0,0 +1,137 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tokio::fs;
+
+use checker_base::checksums::{ChangeSet, Repo, TRACKED_FILES, fetch_file};
+use checker_base::error::Error;
+use checker_base::revision::resolve_change_set;
+
+use crate::output::{ChangesetOutput, EXIT_ERROR, EXIT_SUCCESS, Format, Output};
+
+/// The files written to the output directory.
+#[derive(Debug, Serialize)]
+struct FetchOutput<'a> {
+    moz_rev: &'a str,
+    comm_rev: &'a str,
+    moz_changeset: Option<ChangesetOutput<'a>>,
+    comm_changeset: Option<ChangesetOutput<'a>>,
+    files: Vec<FetchedFile>,
+    error: Option<String>,
+}
+
+/// A file downloaded and written to the output directory, in a
+/// [`FetchOutput`].
+#[derive(Debug, Serialize)]
+struct FetchedFile {
+    repo: &'static str,
+    path: &'static str,
+    destination: PathBuf,
+    size: usize,
+}
+
+impl Output for FetchOutput<'_> {
+    fn to_text(&self) -> String {
+        let revisions = [
+            ("mozilla-central", self.moz_rev, &self.moz_changeset),
+            ("comm-central", self.comm_rev, &self.comm_changeset),
+        ];
+        let mut lines = revisions
+            .into_iter()
+            .filter_map(|(repo, rev, changeset)| {
+                let changeset = changeset.as_ref()?;
+                Some(format!("{repo} revision: {rev} ({})", changeset.to_text()))
+            })
+            .collect::<Vec<_>>();
+
+        lines.extend(self.files.iter().map(|file| {
+            format!(
+                "{}/{}: wrote {} bytes to {}",
+                file.repo,
+                file.path,
+                file.size,
+                file.destination.display()
+            )
+        }));
+
+        if let Some(error) = &self.error {
+            lines.push(format!("Error while fetching files: {error}"));
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Download the file at the given path in the given repo, and write it under
+/// the output directory, in a directory named after the repo.
+async fn fetch_to(
+    change_set: &ChangeSet,
+    repo: Repo,
+    path: &'static str,
+    output_dir: &Path,
+) -> Result<FetchedFile, Error> {
+    let content = fetch_file(change_set, repo, path).await?;
+
+    let destination = output_dir.join(repo.name()).join(path);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(&destination, &content).await?;
+
+    Ok(FetchedFile {
+        repo: repo.name(),
+        path,
+        destination,
+        size: content.len(),
+    })
+}
+
+/// Download all the files the checks use, write them to the output directory,
+/// print the list of written files, and return the exit code.
+///
+/// The revisions are resolved to changesets first, so that all the files of a
+/// repository come from the same changeset.
+pub(crate) async fn fetch(change_set: &ChangeSet, output_dir: &Path, format: Format) -> u8 {
+    let mut output = FetchOutput {
+        moz_rev: change_set.moz_rev(),
+        comm_rev: change_set.tb_rev(),
+        moz_changeset: None,
+        comm_changeset: None,
+        files: vec![],
+        error: None,
+    };
+
+    let resolved = match resolve_change_set(change_set).await {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            log::error!("error while resolving revisions: {err}");
+            output.error = Some(err.to_string());
+            output.print(format);
+            return EXIT_ERROR;
+        }
+    };
+    output.moz_changeset = Some(ChangesetOutput::from(&resolved.moz));
+    output.comm_changeset = Some(ChangesetOutput::from(&resolved.tb));
+
+    for (repo, path) in TRACKED_FILES {
+        match fetch_to(&resolved.change_set, repo, path, output_dir).await {
+            Ok(file) => output.files.push(file),
+            Err(err) => {
+                log::error!("error while fetching {}/{path}: {err}", repo.name());
+                output.error = Some(err.to_string());
+                break;
+            }
+        }
+    }
+
+    output.print(format);
+
+    match output.error {
+        Some(_) => EXIT_ERROR,
+        None => EXIT_SUCCESS,
+    }
+}
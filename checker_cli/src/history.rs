@@ -0,0 +1,291 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+
+use checker_base::checksums::{ChangeSet, Repo, verify_checksums_match};
+use checker_base::error::Error;
+use checker_base::pushlog::{Push, PushBound, fetch_pushes};
+
+use crate::output::{EXIT_ERROR, EXIT_SUCCESS, Format, Output, Status, format_timestamp};
+
+/// How far before the first mozilla-central push to look for the comm-central
+/// push it should be paired with.
+const COMM_LOOKBACK_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// The header of the CSV output.
+const CSV_HEADER: &str =
+    "status,start,end,duration_seconds,moz_push_id,moz_rev,comm_push_id,comm_rev,checks";
+
+/// Parse a bound of the range of pushes to walk: either a push ID, a date
+/// (`YYYY-MM-DD`), or a date and time (`YYYY-MM-DD HH:MM:SS` in UTC, or RFC
+/// 3339).
+pub(crate) fn parse_bound(value: &str) -> Result<PushBound, String> {
+    if let Ok(id) = value.parse() {
+        return Ok(PushBound::Id(id));
+    }
+
+    let timestamp = if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        date.and_time(Default::default()).and_utc().timestamp()
+    } else if let Ok(date) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        date.and_utc().timestamp()
+    } else if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        date.timestamp()
+    } else {
+        return Err("expected a push ID, a date or a date and time".to_string());
+    };
+
+    u64::try_from(timestamp)
+        .map(PushBound::Date)
+        .map_err(|_| "dates before 1970 aren't supported".to_string())
+}
+
+/// A period during which the sync status of the dependencies didn't change.
+#[derive(Debug, Serialize)]
+struct Interval {
+    status: Status,
+
+    /// When the period started, i.e. the date of the push after which the
+    /// status was first seen.
+    start: String,
+
+    /// When the period ended, i.e. the date of the push that changed the
+    /// status. This is `None` for the last period.
+    end: Option<String>,
+
+    /// How long the period lasted, if it has ended.
+    duration_seconds: Option<u64>,
+
+    /// The mozilla-central and comm-central pushes at the start of the period.
+    moz_push_id: u64,
+    moz_rev: String,
+    comm_push_id: u64,
+    comm_rev: String,
+
+    /// The number of checks during the period, i.e. the number of pushes to
+    /// either repository, including the first one.
+    checks: usize,
+
+    #[serde(skip)]
+    start_timestamp: u64,
+}
+
+impl Interval {
+    /// End the period at the given time.
+    fn end_at(&mut self, timestamp: u64) {
+        self.end = Some(format_timestamp(timestamp));
+        self.duration_seconds = Some(timestamp.saturating_sub(self.start_timestamp));
+    }
+}
+
+/// The sync status of the dependencies over a range of pushes.
+#[derive(Debug, Serialize)]
+struct HistoryOutput {
+    intervals: Vec<Interval>,
+    error: Option<String>,
+}
+
+impl Output for HistoryOutput {
+    fn to_text(&self) -> String {
+        let mut lines = self
+            .intervals
+            .iter()
+            .map(|interval| {
+                let status = match interval.status {
+                    Status::InSync => "in sync",
+                    Status::OutOfSync => "out of sync",
+                    Status::Error => "error",
+                };
+
+                format!(
+                    "{} - {}: {status} ({} check(s), from mozilla-central push {} and comm-central push {})",
+                    interval.start,
+                    interval.end.as_deref().unwrap_or("end of range"),
+                    interval.checks,
+                    interval.moz_push_id,
+                    interval.comm_push_id,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        match &self.error {
+            Some(error) => lines.push(format!("Error while walking the pushlogs: {error}")),
+            None if self.intervals.is_empty() => lines.push("No push in this range".to_string()),
+            None => {}
+        }
+
+        lines.join("\n")
+    }
+
+    fn to_csv(&self) -> Option<String> {
+        let mut lines = vec![CSV_HEADER.to_string()];
+        lines.extend(self.intervals.iter().map(|interval| {
+            format!(
+                "{},{},{},{},{},{},{},{},{}",
+                interval.status.as_str(),
+                interval.start,
+                interval.end.as_deref().unwrap_or_default(),
+                interval
+                    .duration_seconds
+                    .map(|duration| duration.to_string())
+                    .unwrap_or_default(),
+                interval.moz_push_id,
+                interval.moz_rev,
+                interval.comm_push_id,
+                interval.comm_rev,
+                interval.checks,
+            )
+        }));
+
+        Some(lines.join("\n"))
+    }
+}
+
+/// A push to either repository.
+enum Event<'a> {
+    Moz(&'a Push),
+    Comm(&'a Push),
+}
+
+impl Event<'_> {
+    fn date(&self) -> u64 {
+        match self {
+            Event::Moz(push) | Event::Comm(push) => push.date,
+        }
+    }
+}
+
+/// Walk the mozilla-central pushes in the given range and the comm-central
+/// pushes that happened meanwhile, check the dependencies after each of them,
+/// and group the results into periods with the same status.
+///
+/// Each push is checked against the tip of the other repository at the time.
+/// Files are downloaded at full changeset hashes, so they're cached if the
+/// change set has a cache.
+async fn timeline(
+    change_set: &ChangeSet,
+    since: PushBound,
+    until: Option<PushBound>,
+) -> Result<Vec<Interval>, Error> {
+    let moz_pushes = fetch_pushes(change_set, Repo::Firefox, since, until).await?;
+    let (Some(first), Some(last)) = (moz_pushes.first(), moz_pushes.last()) else {
+        return Ok(vec![]);
+    };
+
+    // Look a bit before the first mozilla-central push, to find the tip of
+    // comm-central at the time, and stop at the last one, since later
+    // comm-central pushes are outside of the range.
+    let comm_pushes = fetch_pushes(
+        change_set,
+        Repo::Thunderbird,
+        PushBound::Date(first.date.saturating_sub(COMM_LOOKBACK_SECS)),
+        Some(PushBound::Date(last.date)),
+    )
+    .await?;
+
+    let mut events = moz_pushes
+        .iter()
+        .map(Event::Moz)
+        .chain(comm_pushes.iter().map(Event::Comm))
+        .collect::<Vec<_>>();
+    events.sort_by_key(Event::date);
+
+    let mut moz_tip = None;
+    let mut comm_tip = None;
+    let mut intervals: Vec<Interval> = vec![];
+    for event in events {
+        match event {
+            Event::Moz(push) => moz_tip = Some(push),
+            Event::Comm(push) => comm_tip = Some(push),
+        }
+
+        let (Some(moz), Some(comm)) = (moz_tip, comm_tip) else {
+            if let Event::Moz(push) = event {
+                log::warn!(
+                    "Skipping mozilla-central push {} since no earlier comm-central push was found",
+                    push.id
+                );
+            }
+            continue;
+        };
+
+        log::info!(
+            "Checking mozilla-central push {} against comm-central push {}",
+            moz.id,
+            comm.id
+        );
+
+        let pair = ChangeSet {
+            moz_rev: Some(moz.head.clone()),
+            tb_rev: Some(comm.head.clone()),
+            ..change_set.clone()
+        };
+        let status = match verify_checksums_match(pair).await {
+            Ok(report) if report.checksums_match() => Status::InSync,
+            Ok(_) => Status::OutOfSync,
+            Err(err) => {
+                log::error!(
+                    "Error while checking mozilla-central push {}: {err}",
+                    moz.id
+                );
+                Status::Error
+            }
+        };
+
+        let date = event.date();
+        match intervals.last_mut() {
+            Some(interval) if interval.status == status => interval.checks += 1,
+            last => {
+                if let Some(interval) = last {
+                    interval.end_at(date);
+                }
+
+                intervals.push(Interval {
+                    status,
+                    start: format_timestamp(date),
+                    end: None,
+                    duration_seconds: None,
+                    moz_push_id: moz.id,
+                    moz_rev: moz.head.clone(),
+                    comm_push_id: comm.id,
+                    comm_rev: comm.head.clone(),
+                    checks: 1,
+                    start_timestamp: date,
+                });
+            }
+        }
+    }
+
+    Ok(intervals)
+}
+
+/// Print the periods during which the dependencies were in sync or out of sync
+/// over the given range of mozilla-central pushes, and return the exit code.
+pub(crate) async fn history(
+    change_set: &ChangeSet,
+    since: PushBound,
+    until: Option<PushBound>,
+    format: Format,
+) -> u8 {
+    let output = match timeline(change_set, since, until).await {
+        Ok(intervals) => HistoryOutput {
+            intervals,
+            error: None,
+        },
+        Err(err) => {
+            log::error!("error while walking the pushlogs: {err}");
+            HistoryOutput {
+                intervals: vec![],
+                error: Some(err.to_string()),
+            }
+        }
+    };
+    output.print(format);
+
+    match output.error {
+        Some(_) => EXIT_ERROR,
+        None => EXIT_SUCCESS,
+    }
+}
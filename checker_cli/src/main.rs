@@ -2,41 +2,208 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use clap::Parser;
+use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-use checker_base::checksums::{ChangeSet, verify_checksums_match};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+
+use checker_base::cache::{DEFAULT_MAX_SIZE, FileCache};
+use checker_base::checksums::ChangeSet;
+use checker_base::pushlog::PushBound;
 use env_logger::Env;
 
+use crate::output::Format;
+
+mod check;
+mod checksums;
+mod diff;
+mod fetch;
+mod history;
+mod output;
+
+/// The name of the directory to cache files in, in the user's cache directory.
+const CACHE_DIR_NAME: &str = "tb-rust-checker";
+
+/// The number of bytes in a megabyte.
+const MEGABYTE: u64 = 1024 * 1024;
+
+/// Compare the Rust dependencies of comm-central with the ones of
+/// mozilla-central.
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    /// What to do. If omitted, the checksums are compared like with `check`.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// The options shared by all commands.
+#[derive(clap::Args, Debug)]
+struct GlobalArgs {
     /// The mozilla-central revision to use. Defaults to "tip".
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     mozilla_rev: Option<String>,
 
     /// The comm-central revision to use. Defaults to "tip".
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     comm_rev: Option<String>,
+
+    /// The base URL of the Mercurial web frontend serving mozilla-central.
+    /// Defaults to "https://hg-edge.mozilla.org".
+    #[arg(long, global = true, value_name = "URL")]
+    mozilla_host: Option<String>,
+
+    /// The base URL of the Mercurial web frontend serving comm-central.
+    /// Defaults to "https://hg-edge.mozilla.org".
+    #[arg(long, global = true, value_name = "URL")]
+    comm_host: Option<String>,
+
+    /// The maximum size of a downloaded file, in megabytes. Downloads larger
+    /// than this are aborted. There is no limit by default.
+    #[arg(long, global = true, value_name = "MB")]
+    max_file_size: Option<u64>,
+
+    /// The directory to cache downloaded files in. Defaults to
+    /// "tb-rust-checker" in the user's cache directory.
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// The size the cache can grow to before the least recently used files
+    /// are evicted, in megabytes.
+    #[arg(long, global = true, value_name = "MB", default_value_t = DEFAULT_MAX_SIZE / MEGABYTE)]
+    cache_max_size: u64,
+
+    /// Don't cache downloaded files.
+    #[arg(long, global = true, conflicts_with = "cache_dir")]
+    no_cache: bool,
+
+    /// The format to print the result in.
+    #[arg(short, long, value_enum, global = true, default_value_t)]
+    format: Format,
+}
+
+impl GlobalArgs {
+    /// The cache to use, if any.
+    ///
+    /// If no directory is given, the cache is stored in `$XDG_CACHE_HOME`, or
+    /// in `~/.cache` if that's not set. It is disabled if neither of these can
+    /// be figured out.
+    fn cache(&self) -> Option<FileCache> {
+        if self.no_cache {
+            return None;
+        }
+
+        let dir = match &self.cache_dir {
+            Some(dir) => dir.clone(),
+            None => env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?
+                .join(CACHE_DIR_NAME),
+        };
+
+        Some(FileCache::new(
+            dir,
+            self.cache_max_size.saturating_mul(MEGABYTE),
+        ))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare the checksums of the mozilla-central files to the ones stored in
+    /// comm-central.
+    Check,
+
+    /// List the crates locked to different versions in mozilla-central and
+    /// comm-central.
+    Diff,
+
+    /// Print the checksums of the mozilla-central files, i.e. the ones
+    /// comm-central should store to be in sync.
+    Checksums,
+
+    /// Download the files used by the checks, for offline inspection.
+    Fetch {
+        /// The directory to write the files to. Each repository's files are
+        /// written to a directory named after it.
+        #[arg(short, long, default_value = "checker-files")]
+        output: PathBuf,
+    },
+
+    /// Print the periods during which the dependencies were in sync or out of
+    /// sync, over a range of mozilla-central pushes.
+    ///
+    /// The dependencies are checked after each mozilla-central push in the
+    /// range, and after each comm-central push in the meantime, against the tip
+    /// of the other repository at the time. The revision options are ignored.
+    History {
+        /// The first mozilla-central push to check: either a push ID, a date
+        /// (YYYY-MM-DD), or a date and time (YYYY-MM-DD HH:MM:SS, in UTC).
+        #[arg(long, value_name = "DATE|PUSH_ID", value_parser = history::parse_bound)]
+        since: PushBound,
+
+        /// The last mozilla-central push to check, in the same format as
+        /// `--since`. Defaults to the latest push.
+        #[arg(long, value_name = "DATE|PUSH_ID", value_parser = history::parse_bound)]
+        until: Option<PushBound>,
+    },
 }
 
-impl From<Args> for ChangeSet {
-    fn from(value: Args) -> Self {
+impl From<&GlobalArgs> for ChangeSet {
+    fn from(value: &GlobalArgs) -> Self {
         ChangeSet {
-            moz_rev: value.mozilla_rev,
-            tb_rev: value.comm_rev,
+            moz_rev: value.mozilla_rev.clone(),
+            tb_rev: value.comm_rev.clone(),
+            moz_host: value.mozilla_host.clone(),
+            tb_host: value.comm_host.clone(),
+            cache: value.cache(),
+            max_file_size: value
+                .max_file_size
+                .map(|size| size.saturating_mul(MEGABYTE)),
         }
     }
 }
 
+/// Run the given command, print its result to stdout, and exit with a code
+/// reflecting its outcome. Logs go to stderr.
+///
+/// The exit code is 0 if the command succeeded (and found the dependencies in
+/// sync, for commands comparing them), 1 if it found the dependencies out of
+/// sync, and 2 if it couldn't be completed.
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let args = Args::parse();
 
     // Default the log level to "info".
     let env = Env::default().default_filter_or("info");
     env_logger::init_from_env(env);
 
-    match verify_checksums_match(args.into()).await {
-        Ok(ok) => log::info!("checksums match: {ok}"),
-        Err(err) => log::error!("error while verifying files: {err}"),
+    let change_set = ChangeSet::from(&args.global);
+    let format = args.global.format;
+    let command = args.command.unwrap_or(Command::Check);
+
+    if matches!(format, Format::Csv) && !matches!(command, Command::History { .. }) {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "the csv format is only supported by the history command",
+            )
+            .exit();
     }
+
+    let code = match command {
+        Command::Check => check::check(&change_set, format).await,
+        Command::Diff => diff::diff(&change_set, format).await,
+        Command::Checksums => checksums::checksums(&change_set, format).await,
+        Command::Fetch { output } => fetch::fetch(&change_set, &output, format).await,
+        Command::History { since, until } => {
+            history::history(&change_set, since, until, format).await
+        }
+    };
+
+    ExitCode::from(code)
 }
@@ -0,0 +1,132 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use chrono::{DateTime, SecondsFormat};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use checker_base::revision::ResolvedRev;
+
+/// The exit code when the dependencies are in sync, or when a command that
+/// doesn't compare anything succeeds.
+pub(crate) const EXIT_SUCCESS: u8 = 0;
+
+/// The exit code when the dependencies are out of sync.
+pub(crate) const EXIT_OUT_OF_SYNC: u8 = 1;
+
+/// The exit code when the command couldn't be completed.
+pub(crate) const EXIT_ERROR: u8 = 2;
+
+/// The number of characters of a changeset hash to show in text output.
+const SHORT_HASH_LEN: usize = 12;
+
+/// The format to print the result of a command in.
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub(crate) enum Format {
+    /// A human-readable summary.
+    #[default]
+    Text,
+
+    /// A JSON object, see the README file for its schema.
+    Json,
+
+    /// A CSV table. Only supported by the `history` command.
+    Csv,
+}
+
+/// The status of the dependencies after comparing them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    InSync,
+    OutOfSync,
+    Error,
+}
+
+impl Status {
+    /// The status as it appears in the JSON and CSV formats.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::InSync => "in_sync",
+            Status::OutOfSync => "out_of_sync",
+            Status::Error => "error",
+        }
+    }
+
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Status::InSync => EXIT_SUCCESS,
+            Status::OutOfSync => EXIT_OUT_OF_SYNC,
+            Status::Error => EXIT_ERROR,
+        }
+    }
+}
+
+/// The changeset a revision resolved to.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChangesetOutput<'a> {
+    node: &'a str,
+    description: &'a str,
+    push_date: Option<String>,
+}
+
+impl<'a> From<&'a ResolvedRev> for ChangesetOutput<'a> {
+    fn from(rev: &'a ResolvedRev) -> Self {
+        ChangesetOutput {
+            node: &rev.node,
+            description: &rev.description,
+            push_date: rev.push_date.map(format_timestamp),
+        }
+    }
+}
+
+impl ChangesetOutput<'_> {
+    /// Format the changeset as a short summary, e.g. to follow the revision
+    /// that resolved to it.
+    pub fn to_text(&self) -> String {
+        let node = self.node.get(..SHORT_HASH_LEN).unwrap_or(self.node);
+        match &self.push_date {
+            Some(date) => format!("{node}, pushed {date}: {}", self.description),
+            None => format!("{node}: {}", self.description),
+        }
+    }
+}
+
+/// Format a UNIX timestamp as an RFC 3339 date and time in UTC.
+pub(crate) fn format_timestamp(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// The result of a command, which can be printed in any [`Format`].
+///
+/// The JSON format is the serialized result. Results are kept separate from
+/// the types of `checker_base`, so that the output stays stable regardless of
+/// changes to them.
+pub(crate) trait Output: Serialize {
+    /// Format the result as a human-readable summary.
+    fn to_text(&self) -> String;
+
+    /// Format the result as a CSV table, if the command supports it.
+    fn to_csv(&self) -> Option<String> {
+        None
+    }
+
+    /// Print the result to stdout in the given format.
+    fn print(&self, format: Format) {
+        match format {
+            Format::Text => println!("{}", self.to_text()),
+            Format::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(self).expect("output should be serializable")
+            ),
+            Format::Csv => println!(
+                "{}",
+                self.to_csv()
+                    .expect("the CSV format should only be allowed for commands supporting it")
+            ),
+        }
+    }
+}